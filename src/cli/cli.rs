@@ -6,6 +6,10 @@ extern crate vault_core;
 use vault_core::database::Database;
 // use vault_core::database::Configuration;
 use vault_core::record::Record;
+use std::env;
+use std::io;
+use std::io::Write;
+use std::process;
 
 static PASSWORD: &'static str = "My voice is my password, verify me";
 
@@ -14,12 +18,36 @@ pub fn main() {
 
     if create {
         let mut db = Database::create(String::from(PASSWORD));
-        db.add_record(Record::new_login("My First Password".to_string(), "noah".to_string(), "password1".to_string()));
-        db.add_record(Record::new_login("My Second Password".to_string(), "noah".to_string(), "sup3rs3cure".to_string()));
+        db.add_record(Record::new_login("My First Password".to_string(), "noah".to_string(), "password1".to_string())).expect("Should have saved the record");
+        db.add_record(Record::new_login("My Second Password".to_string(), "noah".to_string(), "sup3rs3cure".to_string())).expect("Should have saved the record");
 
         println!("Wrote to the database.")
     } else {
-        let mut db = Database::open(String::from(PASSWORD));
+        // Automation can supply the password through a file descriptor (`--password-fd 3`) or
+        // IRONVAULT_PASSWORD instead
+        let password_fd = match password_fd(env::args().skip(1)) {
+            Ok(fd) => fd,
+            Err(message) => {
+                writeln!(io::stderr(), "{}", message).unwrap_or(());
+                process::exit(1);
+            }
+        };
+
+        let result = if let Some(fd) = password_fd {
+            Database::open_from_fd(fd)
+        } else if env::var_os("IRONVAULT_PASSWORD").is_some() {
+            Database::open_from_env()
+        } else {
+            Database::open(String::from(PASSWORD))
+        };
+
+        let db = match result {
+            Ok(db) => db,
+            Err(err) => {
+                writeln!(io::stderr(), "Could not open the database: {}", err).unwrap_or(());
+                process::exit(1);
+            }
+        };
         let records = db.fetch_records();
 
         println!("Read from the database {} records.", records.len());
@@ -45,3 +73,38 @@ pub fn main() {
     // let other_config = Configuration::from_json(config_json);
     // println!("Other database config: {:?}", other_config);
 }
+
+/// The file descriptor given with `--password-fd N`, if there is one.
+fn password_fd<I: Iterator<Item = String>>(mut args: I) -> Result<Option<i32>, String> {
+    while let Some(arg) = args.next() {
+        if arg == "--password-fd" {
+            return match args.next().map(|fd| fd.parse::<i32>()) {
+                Some(Ok(fd)) if fd >= 0 => Ok(Some(fd)),
+                _ => Err("--password-fd needs a file descriptor number".to_string()),
+            };
+        }
+    }
+
+    return Ok(None);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    describe! password_fd {
+        it "should read the file descriptor" {
+            let args = vec!["--password-fd".to_string(), "3".to_string()];
+            assert_eq!(password_fd(args.into_iter()), Ok(Some(3)));
+        }
+
+        it "should be optional" {
+            assert_eq!(password_fd(Vec::new().into_iter()), Ok(None));
+        }
+
+        it "should reject a missing or invalid file descriptor" {
+            assert!(password_fd(vec!["--password-fd".to_string()].into_iter()).is_err());
+            assert!(password_fd(vec!["--password-fd".to_string(), "three".to_string()].into_iter()).is_err());
+        }
+    }
+}
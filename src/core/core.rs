@@ -3,16 +3,28 @@
 
 #[macro_use]
 extern crate serde_derive;
+#[macro_use]
+extern crate log;
 
+extern crate serde;
 extern crate serde_json;
+extern crate rmp_serde;
+extern crate serde_cbor;
+#[cfg(not(target_arch = "wasm32"))]
+extern crate toml;
 extern crate ring;
-extern crate odds;
 extern crate uuid;
+extern crate unicode_normalization;
+extern crate base64;
+extern crate regex;
 
 // TODO: encrypted_storage should not be `pub`. This is done temporarily for doc generation purposes
 // while working on the 0.2.0 release.
 pub mod encrypted_storage;
 pub mod keys;
+pub mod storage;
 
 pub mod database;
+pub mod shared_database;
 pub mod record;
+pub mod serialization;
@@ -1,27 +1,57 @@
-use std::io::prelude::*;
 use std::io;
 use std::error;
 use std::fmt;
-use std::fs;
-use std::path;
 use std::vec::Vec;
 use ring::aead;
+use ring::digest;
+use ring::hmac;
 use ring::rand;
-use odds::vec::VecExt;
+use keys;
+use storage::Storage;
+
+/// The bytes every encrypted file starts with.
+pub const MAGIC: &'static [u8; 4] = b"IVLT";
+
+/// The version of the encrypted file format written after `MAGIC`.
+pub const FORMAT_VERSION: u8 = 1;
+
+/// The length of the file header: `MAGIC` followed by the `FORMAT_VERSION` byte.
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::path;
+#[cfg(not(target_arch = "wasm32"))]
+use storage::FileStorage;
 
 /// A reference to an encrypted file.
 ///
-/// An instance of `EncryptedStorage` can read or write bytes to the path it was initialized with.
-/// These files are written encrypted with the algorithm and key that are provided to `::new`.
+/// An instance of `EncryptedStorage` can read or write bytes to the `Storage` it was initialized
+/// with (a file at the given path when created with `::new`). The bytes are written encrypted with
+/// the algorithm and key that are provided at construction.
+///
+/// The encrypted data is laid out as `header || nonce || ciphertext || tag`, where the header is
+/// the `MAGIC` bytes followed by a single `FORMAT_VERSION` byte. Files written before the header
+/// was introduced (`nonce || ciphertext || tag`) can still be read. The layout doesn't depend on
+/// the `Storage` backend, so sealed bytes can be moved between backends as they are.
+///
+/// The data can be bound to its purpose with `with_associated_data`: it's authenticated along with
+/// the ciphertext (but not stored), so data written for one purpose can't be read as another, even
+/// under the same key.
+///
+/// `EncryptedStorage` is `Sync`: `read` only borrows it immutably, so it can be shared between
+/// threads that read concurrently. Concurrent writes are left to the `Storage` and should be
+/// serialized by the caller (as `SharedDatabase` does).
 pub struct EncryptedStorage {
-    path: path::PathBuf,
+    storage: Box<Storage>,
     key: Vec<u8>,
     algorithm: &'static aead::Algorithm,
+    associated_data: Vec<u8>,
 }
 
 impl EncryptedStorage {
     /// Creates a new `EncryptedStorage` with the given key and path. The key should be a valid
-    /// CHACHA20_POLY1305 key (256 bits long or 32 bytes long).
+    /// CHACHA20_POLY1305 key (256 bits long or 32 bytes long), unless a different algorithm is
+    /// chosen with `with_algorithm`.
     ///
     /// # Examples
     /// ```rust,no_run
@@ -32,11 +62,93 @@ impl EncryptedStorage {
     /// let key: Vec<u8> = b"7b6300f7dc21c9fddeaa71f439d53b55".to_vec();
     /// EncryptedStorage::new(path, key);
     /// ```
+    #[cfg(not(target_arch = "wasm32"))]
     pub fn new(path: path::PathBuf, key: Vec<u8>) -> EncryptedStorage {
+        return EncryptedStorage::with_storage(Box::new(FileStorage::new(path)), key);
+    }
+
+    /// Creates a new `EncryptedStorage` like `::new` that binds the data to `aad` (e.g. the file name
+    /// or the id of the user it belongs to), like `with_associated_data`.
+    ///
+    /// # Examples
+    /// ```rust,no_run
+    /// use std::path::PathBuf;
+    /// use vault_core::encrypted_storage::EncryptedStorage;
+    ///
+    /// let key: Vec<u8> = b"7b6300f7dc21c9fddeaa71f439d53b55".to_vec();
+    /// let storage = EncryptedStorage::with_aad(PathBuf::from("test/notes"), key, b"user-42".to_vec());
+    /// storage.write(b"Short message").unwrap();
+    /// ```
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn with_aad(path: path::PathBuf, key: Vec<u8>, aad: Vec<u8>) -> EncryptedStorage {
+        let mut storage = EncryptedStorage::new(path, key);
+        storage.associated_data = aad;
+
+        return storage;
+    }
+
+    /// Creates a new `EncryptedStorage` that keeps its encrypted bytes in the given `Storage`
+    /// rather than a file. The key requirements are the same as for `::new`.
+    ///
+    /// # Examples
+    /// ```rust
+    /// use vault_core::encrypted_storage::EncryptedStorage;
+    /// use vault_core::storage::MemoryStorage;
+    ///
+    /// let key: Vec<u8> = b"7b6300f7dc21c9fddeaa71f439d53b55".to_vec();
+    /// let storage = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), key);
+    /// storage.write(b"Short message").unwrap();
+    /// ```
+    pub fn with_storage(storage: Box<Storage>, key: Vec<u8>) -> EncryptedStorage {
         EncryptedStorage {
-            path: path,
+            storage: storage,
             key: key,
             algorithm: &aead::CHACHA20_POLY1305,
+            associated_data: Vec::new(),
+        }
+    }
+
+    /// Use `algorithm` instead of CHACHA20_POLY1305. The key must be the right length for the new
+    /// algorithm (e.g. 16 bytes for AES_128_GCM), and the nonce and tag lengths follow the
+    /// algorithm.
+    ///
+    /// # Examples
+    /// ```rust
+    /// extern crate ring;
+    /// extern crate vault_core;
+    ///
+    /// use vault_core::encrypted_storage::EncryptedStorage;
+    /// use vault_core::storage::MemoryStorage;
+    ///
+    /// # fn main() {
+    /// let key: Vec<u8> = b"9a6a4f0e2c4b8d31".to_vec();
+    /// let storage = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), key)
+    ///     .with_algorithm(&ring::aead::AES_128_GCM);
+    /// storage.write(b"Short message").unwrap();
+    /// # }
+    /// ```
+    pub fn with_algorithm(mut self, algorithm: &'static aead::Algorithm) -> EncryptedStorage {
+        self.algorithm = algorithm;
+        return self;
+    }
+
+    /// Authenticate `associated_data` (e.g. a label naming what the data is for) along with every
+    /// write, instead of nothing. Reads then fail with `StorageError::DecryptionError` unless they
+    /// use the same associated data.
+    pub fn with_associated_data(mut self, associated_data: &[u8]) -> EncryptedStorage {
+        self.associated_data = associated_data.to_vec();
+        return self;
+    }
+
+    /// Creates an `EncryptedStorage` backed by `storage` that uses the same key, algorithm and
+    /// associated data as this one, so the data it holds is encrypted identically (e.g. for a backup
+    /// copy).
+    pub fn sibling(&self, storage: Box<Storage>) -> EncryptedStorage {
+        EncryptedStorage {
+            storage: storage,
+            key: self.key.clone(),
+            algorithm: self.algorithm,
+            associated_data: self.associated_data.clone(),
         }
     }
 
@@ -54,7 +166,7 @@ impl EncryptedStorage {
     /// contents of the file (i.e. the file is not long enough to read the nonce, or the key does not
     /// decrypt the file properly).
     pub fn read<'a>(&self, buffer: &'a mut Vec<u8>) -> Result<&'a [u8], StorageError> {
-        return read_encrypted(&self.path, buffer, &self.key, &self.algorithm);
+        return read_encrypted(&*self.storage, buffer, &self.key, &self.algorithm, &self.associated_data);
     }
 
     /// Writes the given data to the encrypted storage using the CHACHA20_POLY1305 algorithm and the key for
@@ -73,17 +185,190 @@ impl EncryptedStorage {
     /// contents of the file (i.e. the file is not long enough to read the nonce, or the key does not
     /// decrypt the file properly).
     pub fn write(&self, buffer: &[u8]) -> Result<(), StorageError> {
-        return write_encrypted(&self.path, buffer, &self.key, &self.algorithm);
+        return write_encrypted(&*self.storage, buffer, &self.key, &self.algorithm, &self.associated_data);
+    }
+
+    /// Encrypts `buffer` like `write`, but adds it to the end of the storage as a separate entry
+    /// (the sealed bytes preceded by their length, as 4 big endian bytes) instead of replacing what's
+    /// there. Entries are read back with `read_entries`.
+    ///
+    /// # Errors
+    /// The same errors as `write`.
+    pub fn append(&self, buffer: &[u8]) -> Result<(), StorageError> {
+        let entry = try!(self.seal_entry(buffer));
+        return self.append_sealed(&entry);
+    }
+
+    /// Encrypts `buffer` exactly as `write` would, but returns the sealed bytes instead of writing
+    /// them, e.g. to sign them before they're written with `write_sealed`.
+    ///
+    /// # Errors
+    /// The same errors as `write`, other than `StorageError::FileError`.
+    pub fn seal(&self, buffer: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let random = rand::SystemRandom::new();
+        return seal_data(buffer, &self.key, self.algorithm, &self.associated_data, &random);
+    }
+
+    /// Replaces the contents of the storage with bytes from `seal`.
+    ///
+    /// # Errors
+    /// * `StorageError::FileError` if the storage can't be written.
+    pub fn write_sealed(&self, sealed: &[u8]) -> Result<(), StorageError> {
+        return self.storage.write(sealed).map_err(StorageError::FileError);
+    }
+
+    /// Encrypts `buffer` as an entry for `append_sealed`: the sealed bytes preceded by their length.
+    ///
+    /// # Errors
+    /// The same errors as `seal`.
+    pub fn seal_entry(&self, buffer: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let sealed = try!(self.seal(buffer));
+
+        let len = sealed.len() as u32;
+        let mut entry = vec![(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8];
+        entry.extend_from_slice(&sealed);
+
+        return Ok(entry);
+    }
+
+    /// Adds entries from `seal_entry` (one or more, concatenated) to the end of the storage.
+    ///
+    /// # Errors
+    /// * `StorageError::FileError` if the storage can't be written.
+    pub fn append_sealed(&self, entries: &[u8]) -> Result<(), StorageError> {
+        return self.storage.append(entries).map_err(StorageError::FileError);
+    }
+
+    /// Decrypts every entry added with `append`, in order. There are none if nothing has been
+    /// written yet. An entry cut short at the end of the storage (e.g. by a crash while appending)
+    /// is skipped.
+    ///
+    /// # Errors
+    /// The same errors as `read`, if any complete entry can't be read.
+    pub fn read_entries(&self) -> Result<Vec<Vec<u8>>, StorageError> {
+        let mut data: Vec<u8> = Vec::new();
+        match self.storage.read(&mut data) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(StorageError::FileError(err)),
+        }
+
+        let mut entries: Vec<Vec<u8>> = Vec::new();
+        let mut rest = &data[..];
+        while !rest.is_empty() {
+            let len = if rest.len() < 4 { 0 } else {
+                (rest[0] as usize) << 24 | (rest[1] as usize) << 16 | (rest[2] as usize) << 8 | rest[3] as usize
+            };
+            if len == 0 || rest.len() < 4 + len {
+                warn!("Skipping an incomplete entry at the end of the storage");
+                break;
+            }
+
+            let mut sealed = rest[4..4 + len].to_vec();
+            entries.push(try!(open_data(&mut sealed, &self.key, self.algorithm, &self.associated_data)).to_vec());
+            keys::zeroize(&mut sealed);
+
+            rest = &rest[4 + len..];
+        }
+
+        return Ok(entries);
+    }
+
+    /// The number of bytes the encryption adds to the data: the nonce that's stored in front of the
+    /// ciphertext plus the authentication tag after it. The file header (`MAGIC` and the format
+    /// version) comes on top of this.
+    pub fn overhead(&self) -> usize {
+        return self.algorithm.nonce_len() + self.algorithm.tag_len();
+    }
+
+    /// The name of the AEAD algorithm in use, e.g. `"CHACHA20_POLY1305"`.
+    pub fn algorithm_name(&self) -> &'static str {
+        return algorithm_name(self.algorithm);
+    }
+
+    /// An HMAC-SHA256 tag for `data`, keyed with this storage's key. Used to bind data kept outside
+    /// the encrypted storage (e.g. the other files of a database) to the key.
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let signing_key = hmac::SigningKey::new(&digest::SHA256, &self.key);
+        return hmac::sign(&signing_key, data).as_ref().to_vec();
+    }
+
+    /// Returns true if `tag` is the tag `sign` gives for `data`. The comparison is constant time.
+    pub fn verify(&self, data: &[u8], tag: &[u8]) -> bool {
+        let signing_key = hmac::SigningKey::new(&digest::SHA256, &self.key);
+        return hmac::verify_with_own_key(&signing_key, data, tag).is_ok();
+    }
+
+    /// The fingerprint of the key (see `keys::fingerprint`). The key itself is never exposed.
+    pub fn key_fingerprint(&self) -> String {
+        return keys::fingerprint(&self.key);
+    }
+
+    /// This storage's key wrapped by `wrapper`, e.g. to hand the key of a database over to a
+    /// hardware token. Like `key_fingerprint` this doesn't expose the key itself.
+    ///
+    /// # Errors
+    /// Whatever errors `wrapper` returns.
+    pub fn wrap_key(&self, wrapper: &KeyWrapper) -> Result<Vec<u8>, StorageError> {
+        return wrapper.wrap(&self.key);
+    }
+
+    #[cfg(test)]
+    pub fn key(&self) -> &[u8] {
+        return &self.key;
+    }
+}
+
+/// Protects a key (e.g. the record encryption key of a database) with something other than the key
+/// of an `EncryptedStorage`, such as a hardware token or an HSM that keeps its own secret. `wrap`
+/// turns the key into an opaque blob that can be stored anywhere, and `unwrap` gets the key back.
+///
+/// `EncryptedStorage` itself is the default implementation: it seals the key with its own key,
+/// algorithm and associated data, so the blob is exactly what `write` would store. That's how a
+/// password protects the key file of a database.
+pub trait KeyWrapper {
+    /// Wraps `key` into a blob that only `unwrap` can get it back out of.
+    fn wrap(&self, key: &[u8]) -> Result<Vec<u8>, StorageError>;
+
+    /// Gets the key back out of a blob made by `wrap`.
+    ///
+    /// # Errors
+    /// A `StorageError::DecryptionError` (or anything the implementation finds more fitting) if
+    /// `blob` wasn't wrapped by this wrapper.
+    fn unwrap(&self, blob: &[u8]) -> Result<Vec<u8>, StorageError>;
+}
+
+impl KeyWrapper for EncryptedStorage {
+    fn wrap(&self, key: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let random = rand::SystemRandom::new();
+        return seal_data(key, &self.key, self.algorithm, &self.associated_data, &random);
+    }
+
+    fn unwrap(&self, blob: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let mut data = blob.to_vec();
+        let key = try!(open_data(&mut data, &self.key, self.algorithm, &self.associated_data)).to_vec();
+        keys::zeroize(&mut data);
+
+        return Ok(key);
+    }
+}
+
+impl Drop for EncryptedStorage {
+    /// Overwrites the key, so it doesn't linger in freed memory.
+    fn drop(&mut self) {
+        keys::zeroize(&mut self.key);
     }
 }
 
 #[derive(Debug)]
 pub enum StorageError {
     KeyLengthError,
-    KeyError,
-    NonceGenerationError,
-    DecryptionError,
-    EncryptionError,
+    KeyError(&'static str),
+    NonceGenerationError(&'static str),
+    DecryptionError(&'static str),
+    EncryptionError(&'static str),
+    UnsupportedFormat(u8),
+    BadMagic,
     FileError(io::Error),
 }
 
@@ -94,18 +379,24 @@ impl fmt::Display for StorageError {
                 write!(f,
                        "The key was not the right length for the encryption algorithm")
             }
-            StorageError::KeyError => {
+            StorageError::KeyError(context) => {
                 write!(f,
-                       "There was a problem with the key to access the encrypted storage.")
+                       "There was a problem with the key to access the encrypted storage ({}).", context)
+            }
+            StorageError::NonceGenerationError(context) => {
+                write!(f, "There was a problem generating the nonce ({}).", context)
+            }
+            StorageError::DecryptionError(context) => {
+                write!(f, "The encrypted data could not be decrypted ({}).", context)
             }
-            StorageError::NonceGenerationError => {
-                write!(f, "There was a problem generating the nonce.")
+            StorageError::EncryptionError(context) => {
+                write!(f, "The plaintext data could not be encrypted ({}).", context)
             }
-            StorageError::DecryptionError => {
-                write!(f, "The encrypted data could not be decrypted.")
+            StorageError::UnsupportedFormat(version) => {
+                write!(f, "The encrypted data is in an unsupported format (version {}).", version)
             }
-            StorageError::EncryptionError => {
-                write!(f, "The plaintext data could not be encrypted.")
+            StorageError::BadMagic => {
+                write!(f, "The data is not an encrypted vault file.")
             }
             StorageError::FileError(ref err) => {
                 write!(f, "There was an error accessing the file: {}", err)
@@ -120,12 +411,14 @@ impl error::Error for StorageError {
             StorageError::KeyLengthError => {
                 "The key was not the right length for the encryption algorithm"
             }
-            StorageError::KeyError => {
+            StorageError::KeyError(_) => {
                 "There was a problem with the key to access the encrypted storage."
             }
-            StorageError::NonceGenerationError => "There was a problem geenrating the nonce.",
-            StorageError::DecryptionError => "The encrypted data could not be decrypted.",
-            StorageError::EncryptionError => "The plaintext data could not be encrypted.",
+            StorageError::NonceGenerationError(_) => "There was a problem geenrating the nonce.",
+            StorageError::DecryptionError(_) => "The encrypted data could not be decrypted.",
+            StorageError::EncryptionError(_) => "The plaintext data could not be encrypted.",
+            StorageError::UnsupportedFormat(_) => "The encrypted data is in an unsupported format.",
+            StorageError::BadMagic => "The data is not an encrypted vault file.",
             StorageError::FileError(ref err) => err.description(),
         }
     }
@@ -133,93 +426,191 @@ impl error::Error for StorageError {
     fn cause(&self) -> Option<&error::Error> {
         match *self {
             StorageError::KeyLengthError => None,
-            StorageError::KeyError => None,
-            StorageError::NonceGenerationError => None,
-            StorageError::DecryptionError => None,
-            StorageError::EncryptionError => None,
+            StorageError::KeyError(_) => None,
+            StorageError::NonceGenerationError(_) => None,
+            StorageError::DecryptionError(_) => None,
+            StorageError::EncryptionError(_) => None,
+            StorageError::UnsupportedFormat(_) => None,
+            StorageError::BadMagic => None,
             StorageError::FileError(ref err) => Some(err),
         }
     }
 }
 
-fn read_encrypted<'a, P: AsRef<path::Path>>(path: P,
-                                            buffer: &'a mut Vec<u8>,
-                                            key: &[u8],
-                                            algorithm: &'static aead::Algorithm)
-                                            -> Result<&'a [u8], StorageError> {
-    let mut f = try!(fs::File::open(path).map_err(StorageError::FileError));
+/// Seals `plaintext` under `key` (a CHACHA20_POLY1305 key, 32 bytes long) in the same format
+/// `EncryptedStorage` writes, for data that's kept somewhere other than a `Storage`.
+///
+/// # Errors
+/// The same errors as `EncryptedStorage::write`, other than `StorageError::FileError`.
+pub fn seal(plaintext: &[u8], key: &[u8]) -> Result<Vec<u8>, StorageError> {
+    let random = rand::SystemRandom::new();
+    return seal_data(plaintext, key, &aead::CHACHA20_POLY1305, &empty_associated_data(), &random);
+}
 
-    buffer.clear();
-    try!(f.read_to_end(buffer).map_err(StorageError::FileError));
+/// Opens data sealed by `seal` (or read from a file written by `EncryptedStorage` with the default
+/// algorithm).
+///
+/// # Errors
+/// The same errors as `EncryptedStorage::read`, other than `StorageError::FileError`.
+pub fn open(sealed: &[u8], key: &[u8]) -> Result<Vec<u8>, StorageError> {
+    let mut data = sealed.to_vec();
+    let plaintext = try!(open_data(&mut data, key, &aead::CHACHA20_POLY1305, &empty_associated_data())).to_vec();
+    keys::zeroize(&mut data);
 
-    return open_data(buffer, key, algorithm);
+    return Ok(plaintext);
 }
 
-fn write_encrypted<P: AsRef<path::Path>>(path: P,
-                                         buf: &[u8],
-                                         key: &[u8],
-                                         algorithm: &'static aead::Algorithm)
-                                         -> Result<(), StorageError> {
-    let mut f = try!(fs::File::create(path).map_err(StorageError::FileError));
-    let mut data = buf.to_vec();
+fn read_encrypted<'a>(storage: &Storage,
+                      buffer: &'a mut Vec<u8>,
+                      key: &[u8],
+                      algorithm: &'static aead::Algorithm,
+                      associated_data: &[u8])
+                      -> Result<&'a [u8], StorageError> {
+    try!(storage.read(buffer).map_err(StorageError::FileError));
 
-    let ciphertext = try!(seal_data(&mut data, key, algorithm));
+    return open_data(buffer, key, algorithm, associated_data);
+}
 
-    try!(f.write_all(ciphertext).map_err(StorageError::FileError));
+fn write_encrypted(storage: &Storage,
+                   buf: &[u8],
+                   key: &[u8],
+                   algorithm: &'static aead::Algorithm,
+                   associated_data: &[u8])
+                   -> Result<(), StorageError> {
+    let random = rand::SystemRandom::new();
+    let ciphertext = try!(seal_data(buf, key, algorithm, associated_data, &random));
+
+    try!(storage.write(&ciphertext).map_err(StorageError::FileError));
 
     return Ok(());
 }
 
+/// Opens data written by `seal_data`. Data without the header is read as the legacy headerless
+/// format. Data too short to hold a legacy nonce and tag is most likely not a vault file at all,
+/// so `StorageError::BadMagic` is returned; longer data that can't be decrypted is reported as a
+/// `StorageError::DecryptionError`, the same as for data with the header.
 fn open_data<'a>(data: &'a mut Vec<u8>,
                  key: &[u8],
-                 algorithm: &'static aead::Algorithm)
+                 algorithm: &'static aead::Algorithm,
+                 associated_data: &[u8])
                  -> Result<&'a [u8], StorageError> {
 
+    try!(verify_key_len(algorithm, key));
+
+    if data.len() < HEADER_LEN || &data[..MAGIC.len()] != &MAGIC[..] {
+        // TODO: Remove the legacy format after the next release
+        if data.len() < algorithm.nonce_len() + algorithm.tag_len() {
+            return Err(StorageError::BadMagic);
+        }
+
+        return open_payload(&mut data[..], key, algorithm, associated_data);
+    }
+
+    let version = data[MAGIC.len()];
+    if version != FORMAT_VERSION {
+        return Err(StorageError::UnsupportedFormat(version));
+    }
+
+    return open_payload(&mut data[HEADER_LEN..], key, algorithm, associated_data);
+}
+
+/// Opens `nonce || ciphertext || tag`.
+fn open_payload<'a>(data: &'a mut [u8],
+                    key: &[u8],
+                    algorithm: &'static aead::Algorithm,
+                    associated_data: &[u8])
+                    -> Result<&'a [u8], StorageError> {
+
     let nonce_len = algorithm.nonce_len();
 
-    try!(verify_key_len(algorithm, key));
+    if data.len() < nonce_len {
+        return Err(StorageError::DecryptionError("nonce length"));
+    }
 
-    let opening_key = try!(aead::OpeningKey::new(algorithm, &key)
-        .map_err(|_| StorageError::KeyError));
+    let opening_key = try!(opening_key(algorithm, key));
     let nonce = data[..nonce_len].to_vec();
 
     let plaintext = try!(aead::open_in_place(&opening_key,
                                              &nonce,
-                                             &empty_associated_data(),
+                                             associated_data,
                                              nonce_len,
-                                             &mut data[..])
-        .map_err(|_| StorageError::DecryptionError));
+                                             data)
+        .map_err(|_| StorageError::DecryptionError("open_in_place")));
 
     return Ok(plaintext);
 }
 
-fn seal_data<'a>(data: &'a mut Vec<u8>,
-                 key: &[u8],
-                 algorithm: &'static aead::Algorithm)
-                 -> Result<&'a [u8], StorageError> {
+/// Seals `plaintext` as `header || nonce || ciphertext || tag`, with a nonce from `random`.
+/// Everything other than tests should pass a `rand::SystemRandom`.
+///
+/// The output is allocated once at its final size, with the plaintext copied in after the header
+/// and nonce and encrypted in place there, so a large plaintext (e.g. with attachments) is never
+/// reallocated or shifted along. Only the output holds the plaintext, and only until it's
+/// encrypted.
+fn seal_data(plaintext: &[u8],
+             key: &[u8],
+             algorithm: &'static aead::Algorithm,
+             associated_data: &[u8],
+             random: &rand::SecureRandom)
+             -> Result<Vec<u8>, StorageError> {
 
     let nonce_len = algorithm.nonce_len();
     let tag_len = algorithm.tag_len();
+    let prefix_len = HEADER_LEN + nonce_len;
 
     try!(verify_key_len(algorithm, key));
 
-    let sealing_key = try!(aead::SealingKey::new(algorithm, &key)
-        .map_err(|_| StorageError::KeyError));
-    let nonce = try!(generate_nonce(algorithm));
+    let sealing_key = try!(sealing_key(algorithm, key));
+    let nonce = try!(generate_nonce(algorithm, random));
 
-    append_tag_storage(data, algorithm);
+    let sealed_len = try!(plaintext.len().checked_add(prefix_len + tag_len)
+        .ok_or(StorageError::EncryptionError("plaintext length")));
+    let mut data: Vec<u8> = Vec::with_capacity(sealed_len);
+    data.extend_from_slice(&header());
+    data.extend_from_slice(&nonce);
+    data.extend_from_slice(plaintext);
+    append_tag_storage(&mut data, algorithm);
 
     let ciphertext_len = try!(aead::seal_in_place(&sealing_key,
                                                   &nonce,
-                                                  &empty_associated_data(),
-                                                  &mut data[..],
+                                                  associated_data,
+                                                  &mut data[prefix_len..],
                                                   tag_len)
-        .map_err(|_| StorageError::EncryptionError));
+        .map_err(|_| StorageError::EncryptionError("seal_in_place")));
+
+    data.truncate(prefix_len + ciphertext_len);
+
+    return Ok(data);
+}
+
+fn header() -> Vec<u8> {
+    let mut header = MAGIC.to_vec();
+    header.push(FORMAT_VERSION);
+
+    return header;
+}
+
+fn algorithm_name(algorithm: &'static aead::Algorithm) -> &'static str {
+    // `aead::Algorithm` has no name of its own, but each one is a distinct static.
+    let known: [(&'static aead::Algorithm, &'static str); 3] = [(&aead::CHACHA20_POLY1305, "CHACHA20_POLY1305"),
+                                                                 (&aead::AES_128_GCM, "AES_128_GCM"),
+                                                                 (&aead::AES_256_GCM, "AES_256_GCM")];
+
+    for &(candidate, name) in known.iter() {
+        if candidate as *const aead::Algorithm == algorithm as *const aead::Algorithm {
+            return name;
+        }
+    }
+
+    return "unknown";
+}
 
-    data.splice(..0, nonce);
-    let encrypted_len = nonce_len + ciphertext_len;
+fn opening_key(algorithm: &'static aead::Algorithm, key: &[u8]) -> Result<aead::OpeningKey, StorageError> {
+    return aead::OpeningKey::new(algorithm, key).map_err(|_| StorageError::KeyError("OpeningKey::new"));
+}
 
-    return Ok(&data[..encrypted_len]);
+fn sealing_key(algorithm: &'static aead::Algorithm, key: &[u8]) -> Result<aead::SealingKey, StorageError> {
+    return aead::SealingKey::new(algorithm, key).map_err(|_| StorageError::KeyError("SealingKey::new"));
 }
 
 fn verify_key_len(algorithm: &'static aead::Algorithm, key: &[u8]) -> Result<(), StorageError> {
@@ -230,12 +621,11 @@ fn verify_key_len(algorithm: &'static aead::Algorithm, key: &[u8]) -> Result<(),
     return Ok(());
 }
 
-fn generate_nonce(algorithm: &'static aead::Algorithm) -> Result<Vec<u8>, StorageError> {
+fn generate_nonce(algorithm: &'static aead::Algorithm, random: &rand::SecureRandom) -> Result<Vec<u8>, StorageError> {
     let nonce_len = algorithm.nonce_len();
-    let rng = rand::SystemRandom::new();
 
     let mut nonce: Vec<u8> = vec![0; nonce_len];
-    try!(rng.fill(&mut nonce).map_err(|_| StorageError::NonceGenerationError));
+    try!(random.fill(&mut nonce).map_err(|_| StorageError::NonceGenerationError("SecureRandom::fill")));
 
     return Ok(nonce);
 }
@@ -244,17 +634,19 @@ fn empty_associated_data() -> [u8; 0] {
     return [0; 0];
 }
 
+/// Makes room for the tag `aead::seal_in_place` writes after the ciphertext. Callers reserve the
+/// space beforehand, so this doesn't reallocate.
 fn append_tag_storage(plaintext: &mut Vec<u8>, algorithm: &'static aead::Algorithm) {
-    let tag_len = algorithm.tag_len();
-
-    for _ in 0..tag_len {
-        plaintext.push(0);
-    }
+    let len = plaintext.len() + algorithm.tag_len();
+    plaintext.resize(len, 0);
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::io::prelude::*;
+    use std::fs;
+    use storage::MemoryStorage;
 
     describe! new {
         before_each {
@@ -335,10 +727,472 @@ mod test {
             let result = storage.read(&mut sealed_buffer);
 
             assert!(match result.unwrap_err() {
-                StorageError::DecryptionError => true,
+                StorageError::DecryptionError(_) => true,
+                _ => false
+            });
+        }
+    }
+
+    describe! error_context {
+        it "should tag key failures with the operation that failed" {
+            let key: &[u8] = b"too short";
+            let alg = &aead::CHACHA20_POLY1305;
+
+            let opening_context = match opening_key(alg, key) {
+                Err(StorageError::KeyError(context)) => context,
+                _ => panic!("The opening key should not be created"),
+            };
+            let sealing_context = match sealing_key(alg, key) {
+                Err(StorageError::KeyError(context)) => context,
+                _ => panic!("The sealing key should not be created"),
+            };
+
+            assert_eq!(opening_context, "OpeningKey::new");
+            assert_eq!(sealing_context, "SealingKey::new");
+        }
+
+        it "should include the context when displayed" {
+            let err = StorageError::DecryptionError("open_in_place");
+            assert_eq!(err.to_string(), "The encrypted data could not be decrypted (open_in_place).");
+        }
+    }
+
+    describe! format {
+        before_each {
+            ensure_test_dir();
+            let key: &[u8] = b"7b6300f7dc21c9fddeaa71f439d53b55";
+            let storage = EncryptedStorage::new(path::PathBuf::from("test_dir/format"), key.to_vec());
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+            storage.write(b"Short message").expect("The write should be successful");
+            let mut contents: Vec<u8> = Vec::new();
+            fs::File::open("test_dir/format").unwrap().read_to_end(&mut contents).unwrap();
+        }
+
+        after_each {
+            remove_test_dir();
+        }
+
+        it "should start with the magic bytes and version" {
+            assert_eq!(&contents[..MAGIC.len()], MAGIC);
+            assert_eq!(contents[MAGIC.len()], FORMAT_VERSION);
+
+            let plaintext = storage.read(&mut sealed_buffer).expect("The read should be successful");
+            assert_eq!(plaintext, b"Short message");
+        }
+
+        it "should read the legacy headerless format" {
+            fs::File::create("test_dir/format").unwrap().write_all(&contents[HEADER_LEN..]).unwrap();
+
+            let plaintext = storage.read(&mut sealed_buffer).expect("The read should be successful");
+            assert_eq!(plaintext, b"Short message");
+        }
+
+        it "should reject a file that isn't a vault file" {
+            fs::File::create("test_dir/format").unwrap().write_all(b"{\"not\": \"a vault file\"}").unwrap();
+
+            assert!(match storage.read(&mut sealed_buffer).unwrap_err() {
+                StorageError::BadMagic => true,
+                _ => false
+            });
+        }
+
+        it "should report a legacy file that can't be decrypted as a decryption error" {
+            let mut legacy = contents[HEADER_LEN..].to_vec();
+            let last = legacy.len() - 1;
+            legacy[last] ^= 1;
+            fs::File::create("test_dir/format").unwrap().write_all(&legacy).unwrap();
+
+            assert!(match storage.read(&mut sealed_buffer).unwrap_err() {
+                StorageError::DecryptionError(_) => true,
                 _ => false
             });
         }
+
+        it "should reject an unknown format version" {
+            contents[MAGIC.len()] = FORMAT_VERSION + 1;
+            fs::File::create("test_dir/format").unwrap().write_all(&contents).unwrap();
+
+            assert!(match storage.read(&mut sealed_buffer).unwrap_err() {
+                StorageError::UnsupportedFormat(version) => version == FORMAT_VERSION + 1,
+                _ => false
+            });
+        }
+    }
+
+    describe! with_storage {
+        before_each {
+            let key: &[u8] = b"7b6300f7dc21c9fddeaa71f439d53b55";
+            let storage = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), key.to_vec());
+        }
+
+        it "should round trip through an in-memory storage" {
+            storage.write(b"Short message").expect("The write should be successful");
+
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+            let plaintext = storage.read(&mut sealed_buffer).expect("The read should be successful");
+
+            assert_eq!(String::from_utf8_lossy(plaintext), "Short message");
+        }
+
+        it "should return a FileError if nothing has been written" {
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+
+            assert!(match storage.read(&mut sealed_buffer).unwrap_err() {
+                StorageError::FileError(ref err) => err.kind() == io::ErrorKind::NotFound,
+                _ => false
+            });
+        }
+
+        it "should write the same format as a file backed storage" {
+            storage.write(b"Short message").expect("The write should be successful");
+
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+            storage.storage.read(&mut sealed_buffer).unwrap();
+            ensure_test_dir();
+            FileStorage::new(path::PathBuf::from("test_dir/moved")).write(&sealed_buffer).unwrap();
+
+            let file_storage = EncryptedStorage::new(path::PathBuf::from("test_dir/moved"), key.to_vec());
+            let mut buffer: Vec<u8> = Vec::new();
+            let plaintext = file_storage.read(&mut buffer).expect("The read should be successful");
+            assert_eq!(String::from_utf8_lossy(plaintext), "Short message");
+            remove_test_dir();
+        }
+    }
+
+    describe! entries {
+        before_each {
+            let key: &[u8] = b"7b6300f7dc21c9fddeaa71f439d53b55";
+            let storage = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), key.to_vec());
+        }
+
+        it "should read back every appended entry in order" {
+            assert!(storage.read_entries().unwrap().is_empty());
+
+            storage.append(b"first").unwrap();
+            storage.append(b"second").unwrap();
+
+            assert_eq!(storage.read_entries().unwrap(), vec![b"first".to_vec(), b"second".to_vec()]);
+        }
+
+        it "should skip an incomplete entry at the end" {
+            storage.append(b"first").unwrap();
+            storage.storage.append(&[0, 0, 0, 40, 1, 2, 3]).unwrap();
+
+            assert_eq!(storage.read_entries().unwrap(), vec![b"first".to_vec()]);
+        }
+
+        it "should fail to read entries with the wrong key" {
+            storage.append(b"first").unwrap();
+            let mut sealed: Vec<u8> = Vec::new();
+            storage.storage.read(&mut sealed).unwrap();
+
+            let wrong = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), b"00000000000000000000000000000000".to_vec());
+            wrong.storage.write(&sealed).unwrap();
+            assert!(wrong.read_entries().is_err());
+        }
+    }
+
+    describe! sibling {
+        it "should read data written by the original storage" {
+            let key: &[u8] = b"7b6300f7dc21c9fddeaa71f439d53b55";
+            let storage = EncryptedStorage::new(path::PathBuf::from("test_dir/original"), key.to_vec());
+            ensure_test_dir();
+            storage.write(b"Short message").expect("The write should be successful");
+            fs::copy("test_dir/original", "test_dir/copy").unwrap();
+
+            let copy = storage.sibling(Box::new(FileStorage::new(path::PathBuf::from("test_dir/copy"))));
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+            let plaintext = copy.read(&mut sealed_buffer).expect("The read should be successful");
+            assert_eq!(String::from_utf8_lossy(plaintext), "Short message");
+            remove_test_dir();
+        }
+    }
+
+    describe! with_algorithm {
+        it "should round trip data under AES-128-GCM" {
+            let key: &[u8] = b"9a6a4f0e2c4b8d31";
+            let storage = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), key.to_vec())
+                .with_algorithm(&aead::AES_128_GCM);
+
+            storage.write(b"Short message").expect("The write should be successful");
+
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+            let plaintext = storage.read(&mut sealed_buffer).expect("The read should be successful");
+            assert_eq!(String::from_utf8_lossy(plaintext), "Short message");
+        }
+
+        it "should write the AES-128-GCM nonce and tag around the ciphertext" {
+            let key: &[u8] = b"9a6a4f0e2c4b8d31";
+            let storage = EncryptedStorage::new(path::PathBuf::from("test_dir/aes"), key.to_vec())
+                .with_algorithm(&aead::AES_128_GCM);
+            ensure_test_dir();
+
+            storage.write(b"Short message").expect("The write should be successful");
+
+            let contents = fs::metadata("test_dir/aes").unwrap();
+            let alg = &aead::AES_128_GCM;
+            assert_eq!(contents.len() as usize, 5 + alg.nonce_len() + "Short message".len() + alg.tag_len());
+            remove_test_dir();
+        }
+
+        it "should require a 16 byte key for AES-128-GCM" {
+            let key: &[u8] = b"7b6300f7dc21c9fddeaa71f439d53b55";
+            let storage = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), key.to_vec())
+                .with_algorithm(&aead::AES_128_GCM);
+
+            assert!(match storage.write(b"Short message").unwrap_err() {
+                StorageError::KeyLengthError => true,
+                _ => false
+            });
+        }
+    }
+
+    describe! with_associated_data {
+        before_each {
+            ensure_test_dir();
+            let key: &[u8] = b"7b6300f7dc21c9fddeaa71f439d53b55";
+            let storage = EncryptedStorage::new(path::PathBuf::from("test_dir/labeled"), key.to_vec())
+                .with_associated_data(b"label/a");
+            storage.write(b"Short message").expect("The write should be successful");
+        }
+
+        after_each {
+            remove_test_dir();
+        }
+
+        it "should round trip data with the same associated data" {
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+            let plaintext = storage.read(&mut sealed_buffer).expect("The read should be successful");
+            assert_eq!(String::from_utf8_lossy(plaintext), "Short message");
+        }
+
+        it "should not read data written with different associated data" {
+            let other = EncryptedStorage::new(path::PathBuf::from("test_dir/labeled"), key.to_vec())
+                .with_associated_data(b"label/b");
+
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+            assert!(match other.read(&mut sealed_buffer).unwrap_err() {
+                StorageError::DecryptionError(_) => true,
+                _ => false
+            });
+        }
+
+        it "should not read data written with associated data without it" {
+            let other = EncryptedStorage::new(path::PathBuf::from("test_dir/labeled"), key.to_vec());
+
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+            assert!(other.read(&mut sealed_buffer).is_err());
+        }
+    }
+
+    describe! with_aad {
+        before_each {
+            ensure_test_dir();
+            let key: &[u8] = b"7b6300f7dc21c9fddeaa71f439d53b55";
+            let storage = EncryptedStorage::with_aad(path::PathBuf::from("test_dir/aad"), key.to_vec(), b"user-42".to_vec());
+            storage.write(b"Short message").expect("The write should be successful");
+        }
+
+        after_each {
+            remove_test_dir();
+        }
+
+        it "should round trip data with the same aad" {
+            let same = EncryptedStorage::with_aad(path::PathBuf::from("test_dir/aad"), key.to_vec(), b"user-42".to_vec());
+
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+            let plaintext = same.read(&mut sealed_buffer).expect("The read should be successful");
+            assert_eq!(String::from_utf8_lossy(plaintext), "Short message");
+        }
+
+        it "should not read data written with a different aad" {
+            let other = EncryptedStorage::with_aad(path::PathBuf::from("test_dir/aad"), key.to_vec(), b"user-43".to_vec());
+
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+            assert!(match other.read(&mut sealed_buffer).unwrap_err() {
+                StorageError::DecryptionError(_) => true,
+                _ => false
+            });
+        }
+
+        it "should encrypt like with_associated_data" {
+            let labeled = EncryptedStorage::new(path::PathBuf::from("test_dir/aad"), key.to_vec())
+                .with_associated_data(b"user-42");
+
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+            assert!(labeled.read(&mut sealed_buffer).is_ok());
+        }
+    }
+
+    describe! key_wrapper {
+        before_each {
+            let key: &[u8] = b"7b6300f7dc21c9fddeaa71f439d53b55";
+            let wrapping = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), key.to_vec())
+                .with_associated_data(b"label/key");
+        }
+
+        it "should unwrap a wrapped key" {
+            let blob = wrapping.wrap(b"a record encryption key").unwrap();
+
+            assert!(blob != b"a record encryption key".to_vec());
+            assert_eq!(wrapping.unwrap(&blob).unwrap(), b"a record encryption key".to_vec());
+        }
+
+        it "should unwrap what the storage writes" {
+            wrapping.write(b"a record encryption key").unwrap();
+            let mut blob: Vec<u8> = Vec::new();
+            wrapping.storage.read(&mut blob).unwrap();
+
+            assert_eq!(wrapping.unwrap(&blob).unwrap(), b"a record encryption key".to_vec());
+        }
+
+        it "should wrap its own key" {
+            let storage = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), vec![7; 32]);
+            let blob = storage.wrap_key(&wrapping).unwrap();
+
+            assert_eq!(wrapping.unwrap(&blob).unwrap(), vec![7; 32]);
+        }
+
+        it "should not unwrap with a different key" {
+            let blob = wrapping.wrap(b"a record encryption key").unwrap();
+            let other = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), vec![0; 32])
+                .with_associated_data(b"label/key");
+
+            assert!(match other.unwrap(&blob) {
+                Err(StorageError::DecryptionError(_)) => true,
+                _ => false
+            });
+        }
+    }
+
+    describe! seal_data {
+        it "should seal with the nonce from the given source of randomness" {
+            // The AEAD test vector from RFC 7539, section 2.8.2
+            struct FixedNonce;
+            impl rand::SecureRandom for FixedNonce {
+                fn fill(&self, dest: &mut [u8]) -> Result<(), ::ring::error::Unspecified> {
+                    dest.copy_from_slice(&[0x07, 0x00, 0x00, 0x00, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47]);
+                    return Ok(());
+                }
+            }
+
+            let key: Vec<u8> = (0x80..0xa0).collect();
+            let aad = [0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7];
+            let data = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+
+            let sealed = seal_data(&data, &key, &aead::CHACHA20_POLY1305, &aad, &FixedNonce).unwrap();
+
+            let expected: Vec<u8> = vec![
+                0x49, 0x56, 0x4c, 0x54, 0x01,
+                0x07, 0x00, 0x00, 0x00, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47,
+                0xd3, 0x1a, 0x8d, 0x34, 0x64, 0x8e, 0x60, 0xdb, 0x7b, 0x86, 0xaf, 0xbc, 0x53, 0xef, 0x7e, 0xc2,
+                0xa4, 0xad, 0xed, 0x51, 0x29, 0x6e, 0x08, 0xfe, 0xa9, 0xe2, 0xb5, 0xa7, 0x36, 0xee, 0x62, 0xd6,
+                0x3d, 0xbe, 0xa4, 0x5e, 0x8c, 0xa9, 0x67, 0x12, 0x82, 0xfa, 0xfb, 0x69, 0xda, 0x92, 0x72, 0x8b,
+                0x1a, 0x71, 0xde, 0x0a, 0x9e, 0x06, 0x0b, 0x29, 0x05, 0xd6, 0xa5, 0xb6, 0x7e, 0xcd, 0x3b, 0x36,
+                0x92, 0xdd, 0xbd, 0x7f, 0x2d, 0x77, 0x8b, 0x8c, 0x98, 0x03, 0xae, 0xe3, 0x28, 0x09, 0x1b, 0x58,
+                0xfa, 0xb3, 0x24, 0xe4, 0xfa, 0xd6, 0x75, 0x94, 0x55, 0x85, 0x80, 0x8b, 0x48, 0x31, 0xd7, 0xbc,
+                0x3f, 0xf4, 0xde, 0xf0, 0x8e, 0x4b, 0x7a, 0x9d, 0xe5, 0x76, 0xd2, 0x65, 0x86, 0xce, 0xc6, 0x4b,
+                0x61, 0x16,
+                0x1a, 0xe1, 0x0b, 0x59, 0x4f, 0x09, 0xe2, 0x6a, 0x7e, 0x90, 0x2e, 0xcb, 0xd0, 0x60, 0x06, 0x91,
+            ];
+            assert_eq!(sealed, expected);
+        }
+
+        it "should seal a large buffer that opens again" {
+            let key: Vec<u8> = (0x80..0xa0).collect();
+            let plaintext: Vec<u8> = (0..8 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+            let mut sealed = seal_data(&plaintext, &key, &aead::CHACHA20_POLY1305, b"label", &rand::SystemRandom::new()).unwrap();
+            assert_eq!(sealed.len(), HEADER_LEN + 12 + plaintext.len() + 16);
+            assert_eq!(sealed.capacity(), sealed.len());
+
+            let opened = open_data(&mut sealed, &key, &aead::CHACHA20_POLY1305, b"label").unwrap();
+            assert!(opened == &plaintext[..]);
+        }
+
+        it "should produce the same output as sealing in front and splicing the nonce in" {
+            struct FixedNonce;
+            impl rand::SecureRandom for FixedNonce {
+                fn fill(&self, dest: &mut [u8]) -> Result<(), ::ring::error::Unspecified> {
+                    for (i, byte) in dest.iter_mut().enumerate() {
+                        *byte = i as u8;
+                    }
+                    return Ok(());
+                }
+            }
+
+            // How `seal_data` used to seal: in place at the front of the plaintext, with the
+            // header and nonce spliced in before it afterwards
+            fn seal_with_splice(plaintext: &[u8], key: &[u8], algorithm: &'static aead::Algorithm) -> Vec<u8> {
+                let mut data = plaintext.to_vec();
+                let nonce = generate_nonce(algorithm, &FixedNonce).unwrap();
+                append_tag_storage(&mut data, algorithm);
+                let sealing_key = sealing_key(algorithm, key).unwrap();
+                let len = aead::seal_in_place(&sealing_key, &nonce, b"label", &mut data[..], algorithm.tag_len()).unwrap();
+                data.truncate(len);
+                data.splice(..0, nonce.iter().cloned());
+                data.splice(..0, header());
+
+                return data;
+            }
+
+            let key: Vec<u8> = (0x80..0xa0).collect();
+            for algorithm in [&aead::CHACHA20_POLY1305, &aead::AES_256_GCM].iter() {
+                for len in [0, 1, 1024 * 1024 + 3].iter() {
+                    let plaintext: Vec<u8> = (0..*len).map(|i| (i % 251) as u8).collect();
+
+                    assert_eq!(seal_data(&plaintext, &key, algorithm, b"label", &FixedNonce).unwrap(),
+                               seal_with_splice(&plaintext, &key, algorithm));
+                }
+            }
+        }
+    }
+
+    describe! sign {
+        before_each {
+            let key: &[u8] = b"7b6300f7dc21c9fddeaa71f439d53b55";
+            let storage = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), key.to_vec());
+        }
+
+        it "should verify a tag it created" {
+            let tag = storage.sign(b"some data");
+            assert_eq!(tag.len(), 32);
+            assert!(storage.verify(b"some data", &tag));
+        }
+
+        it "should not verify different data or a different key" {
+            let tag = storage.sign(b"some data");
+            assert!(!storage.verify(b"other data", &tag));
+
+            let other = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), b"00000000000000000000000000000000".to_vec());
+            assert!(!other.verify(b"some data", &tag));
+        }
+    }
+
+    describe! introspection {
+        before_each {
+            let key: &[u8] = b"7b6300f7dc21c9fddeaa71f439d53b55";
+            let storage = EncryptedStorage::with_storage(Box::new(MemoryStorage::new()), key.to_vec());
+        }
+
+        it "should report the CHACHA20_POLY1305 nonce and tag overhead" {
+            assert_eq!(storage.overhead(), 12 + 16);
+            assert_eq!(storage.algorithm_name(), "CHACHA20_POLY1305");
+        }
+
+        it "should account for every byte of the sealed data" {
+            storage.write(b"Short message").expect("The write should be successful");
+
+            let mut sealed_buffer: Vec<u8> = Vec::new();
+            storage.storage.read(&mut sealed_buffer).unwrap();
+            assert_eq!(sealed_buffer.len(), HEADER_LEN + storage.overhead() + "Short message".len());
+        }
+
+        it "should follow the chosen algorithm" {
+            let storage = storage.with_algorithm(&aead::AES_256_GCM);
+            assert_eq!(storage.algorithm_name(), "AES_256_GCM");
+            assert_eq!(storage.overhead(), aead::AES_256_GCM.nonce_len() + aead::AES_256_GCM.tag_len());
+        }
     }
 
     fn ensure_test_dir() {
@@ -2,14 +2,26 @@ use std::collections::hash_map::DefaultHasher;
 use std::error;
 use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::ptr;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 use ring::aead;
+use ring::digest;
 use ring::rand;
 use ring::pbkdf2;
+use unicode_normalization::UnicodeNormalization;
 
 // CONFIGURABLE
-const ITERATIONS_BASE_COUNT     : u32 = 100000;
+pub const ITERATIONS_BASE_COUNT : u32 = 100000;
+/// The fewest PBKDF2 iterations a database may be configured with. Anything less is too cheap to
+/// brute-force (and ring refuses `0` outright).
+pub const MIN_ITERATIONS        : u32 = 10000;
 const ITERATIONS_EXTENSION_COUNT: u32 = 10000;
+const SALT_LENGTH               : usize = 16;
+const FINGERPRINT_LENGTH        : usize = 8;
+const CALIBRATION_MAX_ITERATIONS: u32 = 10000000;
+const CALIBRATION_PROBE_ITERATIONS: u32 = 1000;
+const CALIBRATION_MIN_SAMPLE_MILLIS: u64 = 20;
 
 /// Generate a new key for the given algorithm using the given source of randomness.
 ///
@@ -25,33 +37,209 @@ pub fn generate_key(algorithm: &'static aead::Algorithm, random: &rand::SecureRa
     return Ok(encryption_key);
 }
 
+/// Generate a new random salt (16 bytes long) for `derive_key`.
+///
+/// # Errors
+/// * `KeyError::KeyGenerationError` if the source of randomness throws an error
+pub fn generate_salt(random: &rand::SecureRandom) -> Result<Vec<u8>, KeyError> {
+    let mut salt: Vec<u8> = vec![0; SALT_LENGTH];
+    random.fill(&mut salt).map_err(|_| KeyError::KeyGenerationError)?;
+
+    return Ok(salt);
+}
+
 /// Derives a key for the given algorithm, using the provided salt and password. This uses PBKDF2
-/// (HMAC SHA256) to derive the key. The number of iterations is set at 100,000 plus 0-10000 based on
-/// password string (for a total number of iterations between 100,000 and 110,000).
+/// (HMAC SHA256) to derive the key. The number of iterations is set at 100,000 plus 0-9,999 based on
+/// the password (see `iterations`), for a total between 100,000 and 109,999. Prefer an explicit
+/// count (`derive_key_with_iterations`) for anything new.
+///
+/// The password is normalized to Unicode NFKC first, so the same password typed as composed or
+/// decomposed characters (which differs between operating systems) derives the same key.
 ///
 /// # Errors
 /// * `KeyError::SaltLengthError` if the salt is too short (less than or equal to four bytes)
 pub fn derive_key(algorithm: &'static aead::Algorithm, salt: &[u8], password: String) -> Result<Vec<u8>, KeyError> {
+    let password = normalize_owned_password(password);
+    let iterations = iterations(&password);
+    return pbkdf2_derive(algorithm, &pbkdf2::HMAC_SHA256, salt, password, iterations);
+}
+
+/// Derives a key like `derive_key`, but with an explicit number of PBKDF2 iterations instead of
+/// one based on the password.
+///
+/// # Errors
+/// * `KeyError::SaltLengthError` if the salt is too short (less than or equal to four bytes)
+/// * `KeyError::IterationCountError` if `iterations` is zero
+pub fn derive_key_with_iterations(algorithm: &'static aead::Algorithm, salt: &[u8], password: String,
+                                  iterations: u32) -> Result<Vec<u8>, KeyError> {
+    return pbkdf2_derive(algorithm, &pbkdf2::HMAC_SHA256, salt, normalize_owned_password(password), iterations);
+}
+
+/// Derives a key like `derive_key` (or `derive_key_with_iterations` when `iterations` is given),
+/// with `prf` (e.g. `pbkdf2::HMAC_SHA512`) as the PBKDF2 pseudorandom function instead of
+/// HMAC SHA256.
+///
+/// # Errors
+/// * `KeyError::SaltLengthError` if the salt is too short (less than or equal to four bytes)
+/// * `KeyError::IterationCountError` if `iterations` is zero
+pub fn derive_key_with_prf(algorithm: &'static aead::Algorithm, prf: &'static pbkdf2::PRF, salt: &[u8],
+                           password: String, iterations: Option<u32>) -> Result<Vec<u8>, KeyError> {
+    let password = normalize_owned_password(password);
+    let iterations = iterations.unwrap_or_else(|| self::iterations(&password));
+    return pbkdf2_derive(algorithm, prf, salt, password, iterations);
+}
+
+/// Derives a key like `derive_key` (or `derive_key_with_iterations` when `iterations` is given)
+/// from the password's bytes exactly as they were entered, without normalizing them.
+///
+/// This is how every key was derived before passwords were normalized, and is only needed to open
+/// databases created back then.
+///
+/// # Errors
+/// * `KeyError::SaltLengthError` if the salt is too short (less than or equal to four bytes)
+/// * `KeyError::IterationCountError` if `iterations` is zero
+pub fn derive_legacy_key(algorithm: &'static aead::Algorithm, salt: &[u8], password: String,
+                         iterations: Option<u32>) -> Result<Vec<u8>, KeyError> {
+    let iterations = iterations.unwrap_or_else(|| self::iterations(&password));
+    return pbkdf2_derive(algorithm, &pbkdf2::HMAC_SHA256, salt, password, iterations);
+}
+
+/// Overwrites `bytes` with zeros, in a way the compiler won't optimize away, so secrets don't
+/// linger in memory after they're no longer needed.
+pub fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+}
+
+/// Normalizes `password` to Unicode NFKC.
+pub fn normalize_password(password: &str) -> String {
+    // Reserved up front, so the normalized password usually isn't copied (and left behind in
+    // memory) as it grows
+    let mut normalized = String::with_capacity(password.len());
+    normalized.extend(password.nfkc());
+
+    return normalized;
+}
+
+/// Zeroizes `password` and drops it, e.g. when opening a database failed before the key could be
+/// derived from it.
+pub fn discard_password(mut password: String) {
+    unsafe { zeroize(password.as_mut_vec()) };
+}
+
+/// Normalizes `password` like `normalize_password`, zeroizing the original.
+fn normalize_owned_password(password: String) -> String {
+    let normalized = normalize_password(&password);
+    discard_password(password);
+
+    return normalized;
+}
+
+/// Derives the key from `password`, which is zeroized afterwards (whether that worked or not).
+fn pbkdf2_derive(algorithm: &'static aead::Algorithm, prf: &'static pbkdf2::PRF, salt: &[u8], password: String,
+                 iterations: u32) -> Result<Vec<u8>, KeyError> {
     // Just bugger off if you have a weak salt
     if salt.len() <= 4 {
+        discard_password(password);
         return Err(KeyError::SaltLengthError);
     }
 
+    // ring panics on zero iterations
+    if iterations == 0 {
+        discard_password(password);
+        return Err(KeyError::IterationCountError);
+    }
+
     // Create a vector with enough space for our key
     let mut derived_key: Vec<u8> = vec![0; algorithm.key_len()];
 
     // Derive the key using ring (thanks ring!)
-    // CONFIGURABLE (key derivation algorith, PRF (HMAC_SHA256) for key derivation algorithm)
-    pbkdf2::derive(&pbkdf2::HMAC_SHA256, iterations(password.clone()), salt,
+    // CONFIGURABLE (key derivation algorith)
+    // Only the timing is logged: the iteration count may be derived from the password.
+    let started = Instant::now();
+    pbkdf2::derive(prf, iterations, salt,
                        password.as_bytes(), &mut derived_key);
+    let elapsed = started.elapsed();
+    discard_password(password);
+    trace!("Key derivation took {}.{:03}s", elapsed.as_secs(), elapsed.subsec_nanos() / 1_000_000);
 
     return Ok(derived_key);
 }
 
-/// Determine the total number of iterations to use for the given password. Theoretically this will
-/// make GPU attacks more challenging, as the attack process isn't as parallelizable given the need
-/// to branch based on the hash value of the string.
-fn iterations(password: String) -> u32 {
+/// Estimates how many PBKDF2 (HMAC SHA256) iterations take `target` to run on this machine, by
+/// timing short runs and extrapolating. The result is clamped between `MIN_ITERATIONS` and
+/// 10,000,000.
+///
+/// The count should be stored (e.g. as `Configuration::iterations` when creating a database), so
+/// the key is derived the same way on machines of any speed.
+///
+/// # Examples
+/// ```rust
+/// use std::time::Duration;
+/// use vault_core::keys::calibrate_iterations;
+///
+/// let iterations = calibrate_iterations(Duration::from_millis(500));
+/// assert!(iterations >= 10000);
+/// ```
+pub fn calibrate_iterations(target: Duration) -> u32 {
+    let salt = [0u8; SALT_LENGTH];
+    let mut output = [0u8; 32];
+    let minimum_sample = Duration::from_millis(CALIBRATION_MIN_SAMPLE_MILLIS);
+
+    // Keep doubling the probe until it runs long enough to be timed reliably.
+    let mut probe = CALIBRATION_PROBE_ITERATIONS;
+    let mut elapsed;
+    loop {
+        let started = Instant::now();
+        pbkdf2::derive(&pbkdf2::HMAC_SHA256, probe, &salt, b"calibration", &mut output);
+        elapsed = started.elapsed();
+
+        if elapsed >= minimum_sample || probe >= CALIBRATION_MAX_ITERATIONS {
+            break;
+        }
+        probe *= 2;
+    }
+
+    let seconds = |duration: Duration| duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+    let estimate = probe as f64 * seconds(target) / seconds(elapsed).max(1e-9);
+    trace!("Calibrated {} PBKDF2 iterations for {}.{:03}s", estimate as u64,
+           target.as_secs(), target.subsec_nanos() / 1_000_000);
+
+    if estimate < MIN_ITERATIONS as f64 {
+        return MIN_ITERATIONS;
+    }
+    if estimate > CALIBRATION_MAX_ITERATIONS as f64 {
+        return CALIBRATION_MAX_ITERATIONS;
+    }
+
+    return estimate as u32;
+}
+
+/// A short, non-reversible identifier for `key`: the first 8 bytes of its SHA-256 digest, as 16
+/// lowercase hex characters. Two copies of a vault share the same fingerprint exactly when they use
+/// the same key, without the key itself having to be compared or revealed.
+pub fn fingerprint(key: &[u8]) -> String {
+    let digest = digest::digest(&digest::SHA256, key);
+
+    return digest.as_ref()[..FINGERPRINT_LENGTH].iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+}
+
+/// Determine the total number of iterations to use for the given password: the base count plus
+/// 0-9,999 extra iterations picked by a hash of the password.
+///
+/// This was meant to make GPU attacks harder, but anyone guessing passwords can compute the count
+/// for each guess just as cheaply, so it adds next to no security. It is kept only because the keys
+/// of databases created without an explicit iteration count depend on it. The extension is
+/// bounded by the modulo, so the count never exceeds 109,999 however long the password is, and
+/// hashing even a very long password costs a tiny fraction of the key derivation itself.
+///
+/// The count must be identical on every machine that opens a database. The standard library
+/// doesn't promise that `DefaultHasher` stays the same across Rust releases, so the expected counts
+/// are pinned by a test, and newer databases use `password_iterations` instead.
+fn iterations(password: &str) -> u32 {
     // Calculate a (non-secure) hash of the password, to determine how many extra steps we will use
     // based on this password.
     let mut hasher = DefaultHasher::new();
@@ -61,16 +249,73 @@ fn iterations(password: String) -> u32 {
 
     let iterations: u32 = ITERATIONS_BASE_COUNT + iteration_extensions;
 
-    // Ensure we haven't overflowed our u32 size in some way
-    assert!(iterations > ITERATIONS_BASE_COUNT);
+    // Ensure we haven't overflowed our u32 size in some way. The extension can be zero, so the
+    // count may equal the base.
+    assert!(iterations >= ITERATIONS_BASE_COUNT);
 
     return iterations;
 }
 
+/// Determine the number of iterations for the given password like `iterations`, with the extra
+/// iterations picked by the first 4 bytes (big endian) of the password's SHA-256 digest, which is
+/// the same on every platform and Rust release.
+///
+/// # Examples
+/// ```rust
+/// use vault_core::keys::password_iterations;
+///
+/// assert_eq!(password_iterations("hello"), 107114);
+/// ```
+pub fn password_iterations(password: &str) -> u32 {
+    return ITERATIONS_BASE_COUNT + password_hash(password) % ITERATIONS_EXTENSION_COUNT;
+}
+
+/// Determine the number of iterations for the given password like `password_iterations`, with
+/// `base` iterations plus up to `extension_percent` percent of `base` extra, so the extra
+/// iterations keep the same weight whatever the base count. A percentage of `0` gives exactly
+/// `base`.
+///
+/// # Examples
+/// ```rust
+/// use vault_core::keys::extended_iterations;
+///
+/// let count = extended_iterations("hello", 1000000, 25).unwrap();
+/// assert!(count >= 1000000 && count < 1250000);
+/// ```
+///
+/// # Errors
+/// * `KeyError::IterationCountError` if the count could exceed a `u32`.
+pub fn extended_iterations(password: &str, base: u32, extension_percent: u32) -> Result<u32, KeyError> {
+    let extension = base as u64 * extension_percent as u64 / 100;
+    if base as u64 + extension > u32::max_value() as u64 {
+        return Err(KeyError::IterationCountError);
+    }
+
+    if extension == 0 {
+        return Ok(base);
+    }
+
+    let iterations = base + (password_hash(password) as u64 % extension) as u32;
+
+    // The extension was bounded above, so the count can't have wrapped around
+    assert!(iterations >= base);
+
+    return Ok(iterations);
+}
+
+/// The first 4 bytes (big endian) of the password's SHA-256 digest.
+fn password_hash(password: &str) -> u32 {
+    let digest = digest::digest(&digest::SHA256, password.as_bytes());
+    let bytes = digest.as_ref();
+
+    return (bytes[0] as u32) << 24 | (bytes[1] as u32) << 16 | (bytes[2] as u32) << 8 | bytes[3] as u32;
+}
+
 #[derive(Debug)]
 pub enum KeyError {
     KeyGenerationError,
     SaltLengthError,
+    IterationCountError,
 }
 
 impl fmt::Display for KeyError {
@@ -83,6 +328,9 @@ impl fmt::Display for KeyError {
             KeyError::SaltLengthError => {
                 write!(f, "The given salt was too short.")
             }
+            KeyError::IterationCountError => {
+                write!(f, "The iteration count is out of range.")
+            }
         }
     }
 }
@@ -96,6 +344,9 @@ impl error::Error for KeyError {
             KeyError::SaltLengthError => {
                 "The given salt was too short."
             }
+            KeyError::IterationCountError => {
+                "The iteration count is out of range."
+            }
         }
     }
 
@@ -128,21 +379,138 @@ mod test {
         }
     }
 
+    describe! generate_salt {
+        it "should produce different salts of the same length" {
+            let random = &rand::SystemRandom::new();
+            let salt = generate_salt(random).unwrap();
+
+            assert_eq!(salt.len(), SALT_LENGTH);
+            assert!(salt != generate_salt(random).unwrap());
+        }
+    }
+
+    describe! fingerprint {
+        it "should be the start of the SHA-256 digest in hex" {
+            assert_eq!(fingerprint(b"7b6300f7dc21c9fddeaa71f439d53b55"), "91c899cdf40669d7");
+        }
+
+        it "should differ for different keys" {
+            assert!(fingerprint(b"7b6300f7dc21c9fddeaa71f439d53b55") != fingerprint(b"7b6300f7dc21c9fddeaa71f439d53b56"));
+        }
+    }
+
+    describe! normalize_password {
+        it "should derive the same key for composed and decomposed passwords" {
+            let alg = &aead::CHACHA20_POLY1305;
+            let salt: [u8; 16] = [0xd6, 0x26, 0x98, 0xda, 0xf4, 0xdc, 0x50, 0x52, 0x24, 0xf2, 0x27, 0xd1, 0xfe, 0x39, 0x01, 0x8a];
+            let composed = "caf\u{e9}".to_string();
+            let decomposed = "cafe\u{301}".to_string();
+
+            assert!(composed != decomposed);
+            assert_eq!(normalize_password(&composed), normalize_password(&decomposed));
+            assert_eq!(derive_key_with_iterations(alg, &salt, composed.clone(), 1000).unwrap(),
+                       derive_key_with_iterations(alg, &salt, decomposed.clone(), 1000).unwrap());
+            assert!(derive_legacy_key(alg, &salt, composed, Some(1000)).unwrap() !=
+                    derive_legacy_key(alg, &salt, decomposed, Some(1000)).unwrap());
+        }
+
+        it "should fold compatibility characters" {
+            assert_eq!(normalize_password("\u{fb01}le"), "file");
+        }
+    }
+
+    describe! zeroize {
+        it "should overwrite every byte" {
+            let mut secret = b"hunter2".to_vec();
+            zeroize(&mut secret);
+            assert_eq!(secret, vec![0; 7]);
+        }
+    }
+
     describe! iterations {
         it "should produce different iterations for different passwords" {
-            assert!(iterations("hello".to_string()) != iterations("hell".to_string()));
+            assert!(iterations("hello") != iterations("hell"));
         }
 
         it "should produce the same iterations for the same passwords" {
-            assert!(iterations("hello".to_string()) == iterations("hello".to_string()));
+            assert!(iterations("hello") == iterations("hello"));
+        }
+
+        it "should produce the same iterations as every earlier build" {
+            assert_eq!(iterations("hello"), 108841);
+            assert_eq!(iterations("correct horse battery staple"), 102569);
+        }
+
+        it "should bound the iterations for very long passwords" {
+            let password: String = ::std::iter::repeat("a").take(1024 * 1024).collect();
+            let count = iterations(&password);
+
+            assert!(count >= ITERATIONS_BASE_COUNT);
+            assert!(count < ITERATIONS_BASE_COUNT + ITERATIONS_EXTENSION_COUNT);
         }
 
         it "should always produce iterations above the base" {
-            assert!(iterations("hello".to_string()) > ITERATIONS_BASE_COUNT);
-            assert!(iterations("hell".to_string()) > ITERATIONS_BASE_COUNT);
-            assert!(iterations("This is a really quite very long password".to_string()) > ITERATIONS_BASE_COUNT);
-            assert!(iterations("goodbye".to_string()) > ITERATIONS_BASE_COUNT);
-            assert!(iterations("timbuk2".to_string()) > ITERATIONS_BASE_COUNT);
+            assert!(iterations("hello") > ITERATIONS_BASE_COUNT);
+            assert!(iterations("hell") > ITERATIONS_BASE_COUNT);
+            assert!(iterations("This is a really quite very long password") > ITERATIONS_BASE_COUNT);
+            assert!(iterations("goodbye") > ITERATIONS_BASE_COUNT);
+            assert!(iterations("timbuk2") > ITERATIONS_BASE_COUNT);
+        }
+    }
+
+    describe! calibrate_iterations {
+        it "should clamp the iterations to the bounds" {
+            assert_eq!(calibrate_iterations(Duration::from_millis(0)), MIN_ITERATIONS);
+            assert_eq!(calibrate_iterations(Duration::from_secs(24 * 60 * 60)), CALIBRATION_MAX_ITERATIONS);
+        }
+
+        ignore "should scale the iterations with the target" {
+            let short = calibrate_iterations(Duration::from_millis(100));
+            let long = calibrate_iterations(Duration::from_millis(400));
+
+            assert!(short >= MIN_ITERATIONS && long <= CALIBRATION_MAX_ITERATIONS);
+            assert!(long > short * 2 && long < short * 8);
+        }
+    }
+
+    describe! password_iterations {
+        it "should produce the same iterations on every platform" {
+            assert_eq!(password_iterations("hello"), 107114);
+            assert_eq!(password_iterations("correct horse battery staple"), 101567);
+        }
+
+        it "should stay within the extension" {
+            assert!(password_iterations("hello") >= ITERATIONS_BASE_COUNT);
+            assert!(password_iterations("") < ITERATIONS_BASE_COUNT + ITERATIONS_EXTENSION_COUNT);
+        }
+    }
+
+    describe! extended_iterations {
+        it "should stay within the percentage of the base" {
+            for password in ["", "hello", "correct horse battery staple", "timbuk2"].iter() {
+                let count = extended_iterations(password, 1000000, 25).unwrap();
+                assert!(count >= 1000000 && count < 1250000);
+            }
+        }
+
+        it "should match password_iterations for a tenth of the base count" {
+            assert_eq!(extended_iterations("hello", ITERATIONS_BASE_COUNT, 10).unwrap(), password_iterations("hello"));
+            assert_eq!(extended_iterations("correct horse battery staple", ITERATIONS_BASE_COUNT, 10).unwrap(),
+                       password_iterations("correct horse battery staple"));
+        }
+
+        it "should use the base without an extension" {
+            assert_eq!(extended_iterations("hello", 1000, 0).unwrap(), 1000);
+            assert_eq!(extended_iterations("hello", 10, 5).unwrap(), 10);
+        }
+
+        it "should refuse counts that could overflow" {
+            assert!(extended_iterations("hello", u32::max_value() / 2, 100).is_ok());
+            assert!(match extended_iterations("hello", u32::max_value() / 2 + 1, 100) {
+                Err(KeyError::IterationCountError) => true,
+                _ => false,
+            });
+            assert!(extended_iterations("hello", 1000, u32::max_value()).is_err());
         }
     }
 
@@ -182,5 +550,33 @@ mod test {
         ignore "should derive different keys for different passwords" {
             assert!(derive_key(alg, &_salt, "hello".to_string()).unwrap() != derive_key(alg, &_salt, "hell".to_string()).unwrap());
         }
+
+        it "should derive different keys for different iteration counts" {
+            let key_a = derive_key_with_iterations(alg, &_salt, "hello".to_string(), 1000).unwrap();
+            let key_b = derive_key_with_iterations(alg, &_salt, "hello".to_string(), 1001).unwrap();
+
+            assert!(key_a != key_b);
+            assert!(key_a == derive_key_with_iterations(alg, &_salt, "hello".to_string(), 1000).unwrap());
+        }
+
+        it "should refuse zero iterations" {
+            assert!(match derive_key_with_iterations(alg, &_salt, "hello".to_string(), 0) {
+                Err(KeyError::IterationCountError) => true,
+                _ => false,
+            });
+        }
+
+        it "should default to HMAC SHA256" {
+            let key_a = derive_key_with_prf(alg, &pbkdf2::HMAC_SHA256, &_salt, "hello".to_string(), Some(1000)).unwrap();
+            assert!(key_a == derive_key_with_iterations(alg, &_salt, "hello".to_string(), 1000).unwrap());
+        }
+
+        it "should derive the same key for the same password under HMAC SHA512" {
+            let key_a = derive_key_with_prf(alg, &pbkdf2::HMAC_SHA512, &_salt, "hello".to_string(), Some(1000)).unwrap();
+            let key_b = derive_key_with_prf(alg, &pbkdf2::HMAC_SHA512, &_salt, "hello".to_string(), Some(1000)).unwrap();
+
+            assert!(key_a == key_b);
+            assert!(key_a != derive_key_with_iterations(alg, &_salt, "hello".to_string(), 1000).unwrap());
+        }
     }
 }
@@ -0,0 +1,243 @@
+use std::error;
+use std::fmt;
+use std::str;
+use std::vec::Vec;
+use serde::{Serialize, Deserialize};
+use serde_json;
+use rmp_serde;
+use serde_cbor;
+
+/// The UTF-8 byte order mark some editors put at the start of a text file.
+const UTF8_BOM: &'static [u8] = b"\xef\xbb\xbf";
+
+/// The format records are serialized with before they are encrypted. The format a database uses is
+/// stored in its `Configuration` so it can be read back correctly.
+///
+/// `Json` is the default, and is what every database created before the format was configurable
+/// uses. `MessagePack` and `Cbor` are binary formats that take up noticeably less space.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerializationFormat {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Default for SerializationFormat {
+    fn default() -> SerializationFormat {
+        return SerializationFormat::Json;
+    }
+}
+
+impl SerializationFormat {
+    /// Serialize `value` into bytes using this format.
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, SerializationError> {
+        let result = match *self {
+            SerializationFormat::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            SerializationFormat::MessagePack => rmp_serde::to_vec(value).map_err(|err| err.to_string()),
+            SerializationFormat::Cbor => serde_cbor::to_vec(value).map_err(|err| err.to_string()),
+        };
+
+        return result.map_err(|message| SerializationError::new(*self, message));
+    }
+
+    /// Deserialize a value of type `T` from bytes previously produced by `serialize` with this
+    /// format.
+    ///
+    /// JSON must be valid UTF-8. Invalid bytes are reported as such (with
+    /// `SerializationErrorKind::InvalidUtf8`) rather than being replaced, so a corrupted payload
+    /// (or one in a different format) isn't mistaken for a JSON syntax error. A
+    /// leading byte order mark is skipped (see `trim_start`), so JSON written by other tools reads.
+    /// The check borrows `bytes` as a `str` without copying them, so every format is parsed straight
+    /// from the slice and no intermediate `String` is allocated.
+    pub fn deserialize<T: Deserialize>(&self, bytes: &[u8]) -> Result<T, SerializationError> {
+        let result = match *self {
+            SerializationFormat::Json => {
+                let json = try!(str::from_utf8(self.trim_start(bytes)).map_err(|err| SerializationError {
+                    format: *self,
+                    kind: SerializationErrorKind::InvalidUtf8,
+                    message: format!("The data is not valid UTF-8 ({})", err),
+                }));
+                serde_json::from_str(json).map_err(|err| err.to_string())
+            }
+            SerializationFormat::MessagePack => rmp_serde::from_slice(bytes).map_err(|err| err.to_string()),
+            SerializationFormat::Cbor => serde_cbor::from_slice(bytes).map_err(|err| err.to_string()),
+        };
+
+        return result.map_err(|message| SerializationError::new(*self, message));
+    }
+
+    /// `bytes` from where the serialized value starts: for JSON, after a UTF-8 byte order mark and
+    /// any whitespace JSON allows (space, tab, newline and carriage return), e.g. from a file that
+    /// was edited by hand. Binary formats have nothing to skip.
+    pub fn trim_start<'a>(&self, bytes: &'a [u8]) -> &'a [u8] {
+        if *self != SerializationFormat::Json {
+            return bytes;
+        }
+
+        let bytes = if bytes.starts_with(UTF8_BOM) { &bytes[UTF8_BOM.len()..] } else { bytes };
+        let start = bytes.iter().position(|byte| match *byte {
+            b' ' | b'\t' | b'\n' | b'\r' => false,
+            _ => true,
+        }).unwrap_or(bytes.len());
+
+        return &bytes[start..];
+    }
+}
+
+/// What kind of failure a `SerializationError` is, for callers that handle some of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SerializationErrorKind {
+    /// JSON data that isn't valid UTF-8, e.g. because it's corrupt or in another format.
+    InvalidUtf8,
+    /// Any other failure; the message says what it was.
+    Other,
+}
+
+#[derive(Debug)]
+pub struct SerializationError {
+    pub format: SerializationFormat,
+    pub kind: SerializationErrorKind,
+    pub message: String,
+}
+
+impl SerializationError {
+    /// A `SerializationErrorKind::Other` error for `format` with `message`.
+    pub fn new(format: SerializationFormat, message: String) -> SerializationError {
+        return SerializationError { format: format, kind: SerializationErrorKind::Other, message: message };
+    }
+}
+
+impl fmt::Display for SerializationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The data could not be (de)serialized as {:?}: {}", self.format, self.message)
+    }
+}
+
+impl error::Error for SerializationError {
+    fn description(&self) -> &str {
+        return "The data could not be (de)serialized.";
+    }
+
+    fn cause(&self) -> Option<&error::Error> { None }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use record::Record;
+
+    fn records() -> Vec<Record> {
+        let mut records = Vec::new();
+        for i in 0..10 {
+            records.push(Record::new_login(format!("Account {}", i), "myemail@example.com".to_string(), "password1".to_string()));
+        }
+        return records;
+    }
+
+    describe! round_trip {
+        it "should round trip records through json" {
+            let records = records();
+            let bytes = SerializationFormat::Json.serialize(&records).unwrap();
+            let result: Vec<Record> = SerializationFormat::Json.deserialize(&bytes).unwrap();
+
+            assert_eq!(result.len(), records.len());
+            assert_eq!(result[3].uuid, records[3].uuid);
+            assert_eq!(result[3].password(), Some(&"password1".to_string()));
+        }
+
+        it "should round trip records through message pack" {
+            let records = records();
+            let bytes = SerializationFormat::MessagePack.serialize(&records).unwrap();
+            let result: Vec<Record> = SerializationFormat::MessagePack.deserialize(&bytes).unwrap();
+
+            assert_eq!(result.len(), records.len());
+            assert_eq!(result[3].uuid, records[3].uuid);
+            assert_eq!(result[3].password(), Some(&"password1".to_string()));
+        }
+
+        it "should round trip records through cbor" {
+            let records = records();
+            let bytes = SerializationFormat::Cbor.serialize(&records).unwrap();
+            let result: Vec<Record> = SerializationFormat::Cbor.deserialize(&bytes).unwrap();
+
+            assert_eq!(result.len(), records.len());
+            assert_eq!(result[3].uuid, records[3].uuid);
+            assert_eq!(result[3].password(), Some(&"password1".to_string()));
+        }
+
+        it "should deserialize json from a slice the same as from a string" {
+            let records = records();
+            let bytes = SerializationFormat::Json.serialize(&records).unwrap();
+            let from_slice: Vec<Record> = SerializationFormat::Json.deserialize(&bytes).unwrap();
+            let from_string: Vec<Record> = serde_json::from_str(&String::from_utf8(bytes).unwrap()).unwrap();
+
+            assert_eq!(from_slice.len(), from_string.len());
+            for (a, b) in from_slice.iter().zip(from_string.iter()) {
+                assert_eq!(a.uuid, b.uuid);
+                assert_eq!(a.name, b.name);
+                assert_eq!(a.entries, b.entries);
+                assert_eq!(a.updated_at, b.updated_at);
+            }
+        }
+
+        it "should report invalid UTF-8 in json" {
+            let result: Result<Vec<Record>, SerializationError> = SerializationFormat::Json.deserialize(b"[\"\xff\xfe\"]");
+            let err = result.unwrap_err();
+
+            assert_eq!(err.format, SerializationFormat::Json);
+            assert_eq!(err.kind, SerializationErrorKind::InvalidUtf8);
+            assert!(err.message.starts_with("The data is not valid UTF-8"));
+        }
+
+        it "should deserialize json after leading whitespace" {
+            let mut bytes = b" \r\n\t".to_vec();
+            bytes.extend(SerializationFormat::Json.serialize(&records()).unwrap());
+            let result: Vec<Record> = SerializationFormat::Json.deserialize(&bytes).unwrap();
+
+            assert_eq!(result.len(), 10);
+        }
+
+        it "should deserialize json after a byte order mark" {
+            let mut bytes = UTF8_BOM.to_vec();
+            bytes.extend_from_slice(b"\n");
+            bytes.extend(SerializationFormat::Json.serialize(&records()).unwrap());
+            let result: Vec<Record> = SerializationFormat::Json.deserialize(&bytes).unwrap();
+
+            assert_eq!(result.len(), 10);
+        }
+
+        it "should only trim json" {
+            assert_eq!(SerializationFormat::Json.trim_start(b"\xef\xbb\xbf [1]"), b"[1]");
+            assert_eq!(SerializationFormat::Json.trim_start(b"  "), b"");
+            assert_eq!(SerializationFormat::Cbor.trim_start(b" \x80"), b" \x80");
+        }
+
+        it "should only skip the whitespace json allows" {
+            assert_eq!(SerializationFormat::Json.trim_start(b"\x0b\x0c[1]"), b"\x0b\x0c[1]");
+
+            let mut bytes = b"\xa0".to_vec();
+            bytes.extend(SerializationFormat::Json.serialize(&records()).unwrap());
+            let result: Result<Vec<Record>, SerializationError> = SerializationFormat::Json.deserialize(&bytes);
+            assert!(result.is_err());
+        }
+
+        it "should fail to deserialize data in a different format" {
+            let bytes = SerializationFormat::MessagePack.serialize(&records()).unwrap();
+            let result: Result<Vec<Record>, SerializationError> = SerializationFormat::Json.deserialize(&bytes);
+
+            assert_eq!(result.unwrap_err().format, SerializationFormat::Json);
+        }
+    }
+
+    describe! size {
+        it "should be smaller than json for the binary formats" {
+            let records = records();
+            let json = SerializationFormat::Json.serialize(&records).unwrap();
+            let message_pack = SerializationFormat::MessagePack.serialize(&records).unwrap();
+            let cbor = SerializationFormat::Cbor.serialize(&records).unwrap();
+
+            assert!(message_pack.len() < json.len());
+            assert!(cbor.len() < json.len());
+        }
+    }
+}
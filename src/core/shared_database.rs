@@ -0,0 +1,135 @@
+use database::{AddOutcome, Database, DatabaseError};
+use record;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::vec::Vec;
+
+/// A handle to an open `Database` that can be cloned and shared between threads.
+///
+/// The database is only unlocked once; every clone of the handle refers to the same records. Any
+/// number of threads can read at the same time, while a write (e.g. `add_record`) waits for the
+/// readers to finish and blocks new ones until it's done.
+///
+/// # Examples
+/// ```rust,no_run
+/// use std::thread;
+/// use vault_core::database::Database;
+/// use vault_core::shared_database::SharedDatabase;
+///
+/// let shared = SharedDatabase::new(Database::open("password".to_string()).unwrap());
+///
+/// let reader = shared.clone();
+/// thread::spawn(move || {
+///     println!("{} records", reader.read().fetch_records().len());
+/// });
+/// ```
+#[derive(Clone)]
+pub struct SharedDatabase {
+    database: Arc<RwLock<Database>>,
+}
+
+impl SharedDatabase {
+    pub fn new(database: Database) -> SharedDatabase {
+        SharedDatabase { database: Arc::new(RwLock::new(database)) }
+    }
+
+    /// Locks the database for reading, blocking while a write is in progress.
+    ///
+    /// # Panics
+    /// If a thread panicked while holding the write lock.
+    pub fn read(&self) -> RwLockReadGuard<Database> {
+        return self.database.read().expect("The database lock should not be poisoned");
+    }
+
+    /// Locks the database for writing, blocking until every reader is done.
+    ///
+    /// # Panics
+    /// If a thread panicked while holding the write lock.
+    pub fn write(&self) -> RwLockWriteGuard<Database> {
+        return self.database.write().expect("The database lock should not be poisoned");
+    }
+
+    /// A copy of the current records, so the read lock isn't held while they're used.
+    pub fn fetch_records(&self) -> Vec<record::Record> {
+        return self.read().fetch_records().clone();
+    }
+
+    /// Adds `record` while holding the write lock (see `Database::add_record`).
+    ///
+    /// # Errors
+    /// Any error from `Database::add_record`.
+    pub fn add_record(&self, record: record::Record) -> Result<(), DatabaseError> {
+        return self.write().add_record(record);
+    }
+
+    /// Adds `record` unless an equivalent one already exists, while holding the write lock (see
+    /// `Database::add_record_dedup`), so two threads adding the same record can't both add it.
+    ///
+    /// # Errors
+    /// Any error from `Database::add_record_dedup`.
+    pub fn add_record_dedup(&self, record: record::Record) -> Result<AddOutcome, DatabaseError> {
+        return self.write().add_record_dedup(record);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::thread;
+
+    describe! shared_database {
+        before_each {
+            env::remove_var("IRONVAULT_DATABASE");
+            fs::remove_dir_all("test_dir").unwrap_or(());
+            env::set_var("IRONVAULT_DATABASE", "test_dir/shared/ironvault");
+        }
+
+        after_each {
+            fs::remove_dir_all("test_dir").unwrap_or(());
+        }
+
+        ignore "should allow concurrent reads while records are added" {
+            let shared = SharedDatabase::new(Database::create("password".to_string()));
+            let mut threads = Vec::new();
+
+            for _ in 0..4 {
+                let reader = shared.clone();
+                threads.push(thread::spawn(move || {
+                    for _ in 0..50 {
+                        let records = reader.fetch_records();
+                        assert!(records.len() <= 10);
+                        assert!(records.iter().all(|record| record.password() == Some(&"secret".to_string())));
+                    }
+                }));
+            }
+
+            let writer = shared.clone();
+            threads.push(thread::spawn(move || {
+                for i in 0..10 {
+                    let record = record::Record::new_login(format!("Account {}", i), "me".to_string(), "secret".to_string());
+                    writer.add_record(record).expect("Should add the record");
+                }
+            }));
+
+            for thread in threads {
+                thread.join().expect("No thread should panic");
+            }
+
+            assert_eq!(shared.read().fetch_records().len(), 10);
+            assert_eq!(Database::open("password".to_string()).unwrap().fetch_records().len(), 10);
+        }
+
+        ignore "should report a duplicate record instead of adding it" {
+            let shared = SharedDatabase::new(Database::create("password".to_string()));
+            let record = record::Record::new_login("Bank".to_string(), "me".to_string(), "secret".to_string());
+            let uuid = record.uuid.clone();
+
+            let same = record::Record::new_login("Bank".to_string(), "me".to_string(), "secret".to_string());
+
+            assert_eq!(shared.add_record_dedup(record).unwrap(), AddOutcome::Added(uuid.clone()));
+            assert_eq!(shared.add_record_dedup(same).unwrap(), AddOutcome::Duplicate(uuid));
+            assert_eq!(shared.fetch_records().len(), 1);
+        }
+    }
+}
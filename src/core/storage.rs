@@ -0,0 +1,829 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use std::vec::Vec;
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::sync::mpsc;
+#[cfg(not(target_arch = "wasm32"))]
+use std::thread;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Duration;
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::io::prelude::*;
+#[cfg(not(target_arch = "wasm32"))]
+use std::fs;
+#[cfg(not(target_arch = "wasm32"))]
+use std::path;
+#[cfg(not(target_arch = "wasm32"))]
+use ring::rand;
+#[cfg(all(unix, not(target_arch = "wasm32")))]
+use std::os::unix::fs::OpenOptionsExt;
+
+/// A place to keep a single blob of (already encrypted) bytes.
+///
+/// `EncryptedStorage` does all of its cryptography in memory and only hands sealed bytes to a
+/// `Storage`, so anything that can hold a blob (a file, an IndexedDB entry, a buffer in memory)
+/// can back a vault. Hosts without `std::fs` (e.g. `wasm32-unknown-unknown`) supply their own
+/// implementation.
+pub trait Storage: Send + Sync {
+    /// Replaces the contents of `buffer` with everything currently held by the storage.
+    ///
+    /// # Errors
+    /// An `io::ErrorKind::NotFound` error if nothing has been written yet, or any other I/O error
+    /// raised by the backend.
+    fn read(&self, buffer: &mut Vec<u8>) -> io::Result<()>;
+
+    /// Replaces everything held by the storage with `data`.
+    fn write(&self, data: &[u8]) -> io::Result<()>;
+
+    /// Adds `data` to the end of everything held by the storage. By default the contents are read
+    /// and written back with `data` added; backends that can append in place should do so.
+    fn append(&self, data: &[u8]) -> io::Result<()> {
+        let mut contents: Vec<u8> = Vec::new();
+        match self.read(&mut contents) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        contents.extend_from_slice(data);
+        return self.write(&contents);
+    }
+
+    /// When the contents were last written, if the backend keeps track of it.
+    fn modified(&self) -> Option<SystemTime> {
+        return None;
+    }
+
+    /// The number of bytes held by the storage. By default the contents are read to find out;
+    /// backends that know the size without reading should say so.
+    ///
+    /// # Errors
+    /// The same errors as `read`.
+    fn size(&self) -> io::Result<u64> {
+        let mut contents: Vec<u8> = Vec::new();
+        try!(self.read(&mut contents));
+
+        return Ok(contents.len() as u64);
+    }
+
+    /// Removes everything held by the storage, making a best effort to leave nothing recoverable
+    /// behind. Erasing storage that was never written to is not an error. By default the contents
+    /// are replaced with nothing, which leaves the storage empty rather than gone; backends that
+    /// can remove what they hold should do so.
+    fn erase(&self) -> io::Result<()> {
+        return self.write(&[]);
+    }
+}
+
+/// A shared `Storage` is a `Storage` as well, e.g. to hand out the same `MemoryStorage` every time
+/// a `StorageFactory` is asked for a file.
+impl<S: Storage + ?Sized> Storage for Arc<S> {
+    fn read(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        return (**self).read(buffer);
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        return (**self).write(data);
+    }
+
+    fn append(&self, data: &[u8]) -> io::Result<()> {
+        return (**self).append(data);
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        return (**self).modified();
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        return (**self).size();
+    }
+
+    fn erase(&self) -> io::Result<()> {
+        return (**self).erase();
+    }
+}
+
+/// A `Storage` backed by a single file on the local filesystem.
+///
+/// Writes go to a temporary file next to the destination (with `.tmp` appended to its name),
+/// which is then renamed over it. Readers therefore always see either the old or the new
+/// contents, never a partially written file. Keeping the temporary file in the same directory
+/// keeps the rename atomic, which it isn't across filesystems. On Unix the files are only
+/// readable by their owner (mode 0600).
+///
+/// By default the file (and the directory holding it) is synced to disk before a write returns, so
+/// a successful write survives a power loss. See `with_durable` to skip that.
+///
+/// Not available on `wasm32` targets, which have no filesystem.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct FileStorage {
+    path: path::PathBuf,
+    durable: bool,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl FileStorage {
+    pub fn new(path: path::PathBuf) -> FileStorage {
+        FileStorage { path: path, durable: true }
+    }
+
+    /// Whether writes wait for the data to reach the disk (the default). Syncing is expensive, so
+    /// e.g. a bulk import may want to turn it off and accept that the last writes can be lost if
+    /// the machine goes down.
+    pub fn with_durable(mut self, durable: bool) -> FileStorage {
+        self.durable = durable;
+        return self;
+    }
+
+    fn sync(&self) -> &Fn(&fs::File) -> io::Result<()> {
+        return if self.durable { &sync_file } else { &skip_sync };
+    }
+
+    pub fn path(&self) -> &path::Path {
+        return &self.path;
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Storage for FileStorage {
+    fn read(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let mut f = try!(fs::File::open(&self.path));
+
+        buffer.clear();
+        try!(f.read_to_end(buffer));
+
+        return Ok(());
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        return write_file(&self.path, data, self.sync());
+    }
+
+    /// Appends to the file in place, so only `data` is written. Unlike `write` this isn't atomic: a
+    /// crash part way through can leave part of `data` at the end of the file.
+    fn append(&self, data: &[u8]) -> io::Result<()> {
+        let mut f = try!(private_options().append(true).create(true).open(&self.path));
+        try!(f.write_all(data));
+
+        return (self.sync())(&f);
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        return fs::metadata(&self.path).and_then(|metadata| metadata.modified()).ok();
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        return fs::metadata(&self.path).map(|metadata| metadata.len());
+    }
+
+    /// Overwrites the file in place with random bytes before removing it.
+    fn erase(&self) -> io::Result<()> {
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        let mut noise: Vec<u8> = vec![0; metadata.len() as usize];
+        try!(rand::SystemRandom::new().fill(&mut noise)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Could not generate random data")));
+
+        let mut f = try!(fs::OpenOptions::new().write(true).open(&self.path));
+        try!(f.write_all(&noise));
+        try!(f.sync_all());
+
+        return fs::remove_file(&self.path);
+    }
+}
+
+/// Writes `data` to a temporary file that's renamed over `path`, calling `sync` with the file once
+/// it's written and with the directory once it's renamed.
+#[cfg(not(target_arch = "wasm32"))]
+fn write_file(path: &path::Path, data: &[u8], sync: &Fn(&fs::File) -> io::Result<()>) -> io::Result<()> {
+    let mut temporary_path = path.to_path_buf().into_os_string();
+    temporary_path.push(".tmp");
+
+    // A temporary file left behind by a crash may have other permissions (or be a symlink placed by
+    // someone else), so it's replaced rather than reused.
+    match fs::remove_file(&temporary_path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {},
+        result => try!(result),
+    }
+
+    {
+        let mut f = try!(private_options().write(true).create_new(true).open(&temporary_path));
+        try!(f.write_all(data));
+        try!(sync(&f));
+    }
+    try!(fs::rename(&temporary_path, path));
+
+    return sync_directory(path, sync);
+}
+
+/// `OpenOptions` that create files only their owner can read and write.
+#[cfg(all(unix, not(target_arch = "wasm32")))]
+fn private_options() -> fs::OpenOptions {
+    let mut options = fs::OpenOptions::new();
+    options.mode(0o600);
+
+    return options;
+}
+
+#[cfg(all(not(unix), not(target_arch = "wasm32")))]
+fn private_options() -> fs::OpenOptions {
+    return fs::OpenOptions::new();
+}
+
+/// Makes the rename of a file in the directory durable. Only possible on Unix, elsewhere
+/// directories can't be opened.
+#[cfg(all(unix, not(target_arch = "wasm32")))]
+fn sync_directory(path: &path::Path, sync: &Fn(&fs::File) -> io::Result<()>) -> io::Result<()> {
+    let directory = match path.parent() {
+        Some(parent) if parent != path::Path::new("") => parent,
+        _ => path::Path::new("."),
+    };
+
+    return sync(&try!(fs::File::open(directory)));
+}
+
+#[cfg(all(not(unix), not(target_arch = "wasm32")))]
+fn sync_directory(_path: &path::Path, _sync: &Fn(&fs::File) -> io::Result<()>) -> io::Result<()> {
+    return Ok(());
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn sync_file(file: &fs::File) -> io::Result<()> {
+    return file.sync_all();
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn skip_sync(_file: &fs::File) -> io::Result<()> {
+    return Ok(());
+}
+
+/// A `Storage` that gives up on any operation of another `Storage` that takes longer than
+/// `timeout`, e.g. a network filesystem or an HTTP backend that stops responding.
+///
+/// Each operation runs on its own thread, and fails with an `io::ErrorKind::TimedOut` error if it
+/// hasn't finished in time. The thread itself can't be interrupted, so a hung operation keeps it
+/// alive until the backend returns (and a write may still land after it was reported as failed).
+///
+/// Not available on `wasm32` targets, which have no threads.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct TimeoutStorage {
+    storage: Arc<Box<Storage>>,
+    timeout: Duration,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl TimeoutStorage {
+    pub fn new(storage: Box<Storage>, timeout: Duration) -> TimeoutStorage {
+        TimeoutStorage { storage: Arc::new(storage), timeout: timeout }
+    }
+
+    fn run<T, F>(&self, operation: F) -> io::Result<T>
+        where T: Send + 'static, F: FnOnce(&Storage) -> io::Result<T> + Send + 'static {
+        let (sender, receiver) = mpsc::channel();
+        let storage = self.storage.clone();
+        thread::spawn(move || {
+            // The receiver is gone if we already gave up waiting
+            sender.send(operation(&**storage)).unwrap_or(());
+        });
+
+        return match receiver.recv_timeout(self.timeout) {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "The storage didn't respond in time")),
+        };
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Storage for TimeoutStorage {
+    fn read(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        *buffer = try!(self.run(|storage| {
+            let mut data: Vec<u8> = Vec::new();
+            try!(storage.read(&mut data));
+            return Ok(data);
+        }));
+
+        return Ok(());
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let data = data.to_vec();
+        return self.run(move |storage| storage.write(&data));
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        return self.run(|storage| Ok(storage.modified())).unwrap_or(None);
+    }
+
+    fn erase(&self) -> io::Result<()> {
+        return self.run(|storage| storage.erase());
+    }
+}
+
+/// A `Storage` that only lives in memory. Useful for tests, and for hosts that want to handle
+/// persistence themselves.
+pub struct MemoryStorage {
+    data: Mutex<Option<Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> MemoryStorage {
+        MemoryStorage { data: Mutex::new(None) }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let data = self.data.lock().expect("MemoryStorage lock should not be poisoned");
+
+        match *data {
+            Some(ref bytes) => {
+                buffer.clear();
+                buffer.extend_from_slice(bytes);
+                return Ok(());
+            }
+            None => {
+                return Err(io::Error::new(io::ErrorKind::NotFound,
+                                          "Nothing has been written to this storage"));
+            }
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let mut contents = self.data.lock().expect("MemoryStorage lock should not be poisoned");
+        *contents = Some(data.to_vec());
+
+        return Ok(());
+    }
+
+    fn erase(&self) -> io::Result<()> {
+        let mut contents = self.data.lock().expect("MemoryStorage lock should not be poisoned");
+        *contents = None;
+
+        return Ok(());
+    }
+}
+
+/// Several named sections (e.g. a database's `config`, `key` and `storage`) kept together in one
+/// `Storage`, so a whole database can live in a single file. Each `BundleSection` is a `Storage`
+/// of its own.
+///
+/// The bundle starts with `BUNDLE_MAGIC`, followed by each section in order of name: the length of
+/// the name (4 bytes), the name, the length of the contents (8 bytes) and the contents, lengths
+/// big-endian. The sections are stored as they're written, so encrypted sections stay encrypted.
+///
+/// Every write rewrites the whole bundle, so it's as atomic as a write to the underlying storage.
+pub struct Bundle {
+    storage: Box<Storage>,
+    lock: Mutex<()>,
+}
+
+/// The bytes every `Bundle` starts with.
+pub const BUNDLE_MAGIC: &'static [u8] = b"IRONVAULT-BUNDLE-1\n";
+
+impl Bundle {
+    pub fn new(storage: Box<Storage>) -> Bundle {
+        Bundle { storage: storage, lock: Mutex::new(()) }
+    }
+
+    /// The section called `name`, which doesn't have to exist yet.
+    pub fn section(bundle: &Arc<Bundle>, name: &str) -> BundleSection {
+        BundleSection { bundle: bundle.clone(), name: name.to_string() }
+    }
+
+    /// Every section in the bundle, none if nothing has been written yet.
+    ///
+    /// # Errors
+    /// An `io::ErrorKind::InvalidData` error if the bundle is malformed, or any error reading the
+    /// underlying storage.
+    pub fn sections(&self) -> io::Result<BTreeMap<String, Vec<u8>>> {
+        let mut contents: Vec<u8> = Vec::new();
+        match self.storage.read(&mut contents) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(err) => return Err(err),
+        }
+
+        return decode_bundle(&contents);
+    }
+
+    /// Replaces the section `name` with `contents`, or removes it if `contents` is `None`. The
+    /// underlying storage is erased once the last section is gone.
+    fn update(&self, name: &str, contents: Option<&[u8]>) -> io::Result<()> {
+        let _guard = self.lock.lock().expect("The bundle lock should not be poisoned");
+        let mut sections = try!(self.sections());
+        match contents {
+            Some(contents) => { sections.insert(name.to_string(), contents.to_vec()); }
+            None => {
+                if sections.remove(name).is_none() {
+                    return Ok(());
+                }
+            }
+        }
+
+        if sections.is_empty() {
+            return self.storage.erase();
+        }
+
+        return self.storage.write(&encode_bundle(&sections));
+    }
+}
+
+/// A named section of a `Bundle`.
+///
+/// Erasing a section rewrites the bundle without it, so (unlike `FileStorage::erase`) the removed
+/// bytes aren't overwritten in place until the last section is erased.
+pub struct BundleSection {
+    bundle: Arc<Bundle>,
+    name: String,
+}
+
+impl Storage for BundleSection {
+    fn read(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let mut sections = try!(self.bundle.sections());
+        match sections.remove(&self.name) {
+            Some(contents) => {
+                *buffer = contents;
+                return Ok(());
+            }
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, format!("The bundle has no {} section", self.name))),
+        }
+    }
+
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        return self.bundle.update(&self.name, Some(data));
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        return self.bundle.storage.modified();
+    }
+
+    fn erase(&self) -> io::Result<()> {
+        return self.bundle.update(&self.name, None);
+    }
+}
+
+fn encode_bundle(sections: &BTreeMap<String, Vec<u8>>) -> Vec<u8> {
+    let mut bytes: Vec<u8> = BUNDLE_MAGIC.to_vec();
+    for (name, contents) in sections.iter() {
+        push_length(&mut bytes, name.len() as u64, 4);
+        bytes.extend_from_slice(name.as_bytes());
+        push_length(&mut bytes, contents.len() as u64, 8);
+        bytes.extend_from_slice(contents);
+    }
+
+    return bytes;
+}
+
+/// # Errors
+/// An `io::ErrorKind::InvalidData` error if `bytes` don't start with `BUNDLE_MAGIC`, a section
+/// is cut short or a name isn't UTF-8.
+fn decode_bundle(bytes: &[u8]) -> io::Result<BTreeMap<String, Vec<u8>>> {
+    if !bytes.starts_with(BUNDLE_MAGIC) {
+        return Err(invalid_bundle("The file is not a bundle"));
+    }
+
+    let mut sections = BTreeMap::new();
+    let mut rest = &bytes[BUNDLE_MAGIC.len()..];
+    while !rest.is_empty() {
+        let name = try!(take_section(&mut rest, 4));
+        let name = try!(String::from_utf8(name.to_vec()).map_err(|_| invalid_bundle("A section name is not UTF-8")));
+        let contents = try!(take_section(&mut rest, 8));
+        sections.insert(name, contents.to_vec());
+    }
+
+    return Ok(sections);
+}
+
+fn push_length(bytes: &mut Vec<u8>, length: u64, width: usize) {
+    for i in (0..width).rev() {
+        bytes.push((length >> (i * 8)) as u8);
+    }
+}
+
+/// Takes a length of `width` bytes and then that many bytes from the front of `bytes`.
+fn take_section<'a>(bytes: &mut &'a [u8], width: usize) -> io::Result<&'a [u8]> {
+    if bytes.len() < width {
+        return Err(invalid_bundle("A section is cut short"));
+    }
+
+    let length = bytes[..width].iter().fold(0u64, |length, byte| (length << 8) | *byte as u64);
+    let rest = &bytes[width..];
+    if (rest.len() as u64) < length {
+        return Err(invalid_bundle("A section is cut short"));
+    }
+
+    let (section, rest) = rest.split_at(length as usize);
+    *bytes = rest;
+    return Ok(section);
+}
+
+fn invalid_bundle(message: &str) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    describe! memory_storage {
+        before_each {
+            let storage = MemoryStorage::new();
+            let mut buffer: Vec<u8> = Vec::new();
+        }
+
+        it "should return NotFound before anything is written" {
+            let error = storage.read(&mut buffer).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        }
+
+        it "should read back what was written" {
+            storage.write(b"some bytes").unwrap();
+            storage.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"some bytes".to_vec());
+        }
+
+        it "should replace the previous contents on write" {
+            storage.write(b"some bytes").unwrap();
+            storage.write(b"other").unwrap();
+            storage.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"other".to_vec());
+        }
+
+        it "should append to the previous contents" {
+            storage.append(b"some").unwrap();
+            storage.append(b" bytes").unwrap();
+            storage.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"some bytes".to_vec());
+        }
+
+        it "should return NotFound after being erased" {
+            storage.write(b"some bytes").unwrap();
+            storage.erase().unwrap();
+
+            let error = storage.read(&mut buffer).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        }
+    }
+
+    describe! default_erase {
+        it "should leave the storage empty" {
+            struct WriteOnlyStorage(MemoryStorage);
+
+            impl Storage for WriteOnlyStorage {
+                fn read(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+                    return self.0.read(buffer);
+                }
+
+                fn write(&self, data: &[u8]) -> io::Result<()> {
+                    return self.0.write(data);
+                }
+            }
+
+            let storage = WriteOnlyStorage(MemoryStorage::new());
+            let mut buffer: Vec<u8> = Vec::new();
+            storage.erase().unwrap();
+
+            storage.write(b"some bytes").unwrap();
+            storage.erase().unwrap();
+            storage.read(&mut buffer).unwrap();
+            assert!(buffer.is_empty());
+        }
+    }
+
+    describe! shared_storage {
+        it "should share the contents between its clones" {
+            let storage = Arc::new(MemoryStorage::new());
+            let other = storage.clone();
+            let mut buffer: Vec<u8> = Vec::new();
+
+            storage.write(b"some bytes").unwrap();
+            other.append(b" more").unwrap();
+            storage.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"some bytes more".to_vec());
+        }
+    }
+
+    describe! timeout_storage {
+        before_each {
+            let mut buffer: Vec<u8> = Vec::new();
+        }
+
+        it "should pass through operations that finish in time" {
+            let storage = TimeoutStorage::new(Box::new(MemoryStorage::new()), Duration::from_secs(5));
+            storage.write(b"some bytes").unwrap();
+            storage.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"some bytes".to_vec());
+
+            storage.erase().unwrap();
+            assert_eq!(storage.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::NotFound);
+        }
+
+        it "should time out operations that take too long" {
+            struct SlowStorage;
+
+            impl Storage for SlowStorage {
+                fn read(&self, _buffer: &mut Vec<u8>) -> io::Result<()> {
+                    thread::sleep(Duration::from_millis(500));
+                    return Ok(());
+                }
+
+                fn write(&self, _data: &[u8]) -> io::Result<()> {
+                    return Ok(());
+                }
+
+                fn erase(&self) -> io::Result<()> {
+                    return Ok(());
+                }
+            }
+
+            let storage = TimeoutStorage::new(Box::new(SlowStorage), Duration::from_millis(20));
+            assert_eq!(storage.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::TimedOut);
+            storage.write(b"fast").unwrap();
+        }
+    }
+
+    describe! file_storage {
+        before_each {
+            fs::remove_dir_all("test_dir").unwrap_or(());
+            fs::create_dir_all("test_dir").unwrap_or(());
+            let storage = FileStorage::new(path::PathBuf::from("test_dir/file_storage"));
+            let mut buffer: Vec<u8> = Vec::new();
+        }
+
+        after_each {
+            fs::remove_dir_all("test_dir").unwrap_or(());
+        }
+
+        it "should return NotFound before anything is written" {
+            let error = storage.read(&mut buffer).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        }
+
+        it "should read back what was written" {
+            storage.write(b"some bytes").unwrap();
+            assert!(storage.path().is_file());
+
+            storage.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"some bytes".to_vec());
+        }
+
+        it "should report the size of the file" {
+            assert_eq!(storage.size().unwrap_err().kind(), io::ErrorKind::NotFound);
+
+            storage.write(b"some bytes").unwrap();
+            assert_eq!(storage.size().unwrap(), 10);
+        }
+
+        it "should not leave the temporary file behind" {
+            storage.write(b"some bytes").unwrap();
+            storage.write(b"other").unwrap();
+
+            assert!(!path::Path::new("test_dir/file_storage.tmp").exists());
+            storage.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"other".to_vec());
+        }
+
+        it "should sync the file and its directory when writing" {
+            let syncs = Mutex::new(0);
+            let count = |_: &fs::File| { *syncs.lock().unwrap() += 1; return Ok(()); };
+
+            write_file(storage.path(), b"some bytes", &count).unwrap();
+
+            assert_eq!(*syncs.lock().unwrap(), if cfg!(unix) { 2 } else { 1 });
+            storage.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"some bytes".to_vec());
+        }
+
+        it "should read back what was written without syncing" {
+            let storage = FileStorage::new(path::PathBuf::from("test_dir/file_storage")).with_durable(false);
+            storage.write(b"some bytes").unwrap();
+            storage.append(b" more").unwrap();
+
+            storage.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"some bytes more".to_vec());
+        }
+
+        it "should create the temporary and final files readable only by their owner" {
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+
+                let sync_called = Mutex::new(false);
+                let check = |file: &fs::File| {
+                    let mode = file.metadata().unwrap().permissions().mode();
+                    if !*sync_called.lock().unwrap() {
+                        assert_eq!(mode & 0o777, 0o600);
+                    }
+                    *sync_called.lock().unwrap() = true;
+                    return Ok(());
+                };
+                write_file(storage.path(), b"some bytes", &check).unwrap();
+                assert!(*sync_called.lock().unwrap());
+
+                let mode = fs::metadata(storage.path()).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o600);
+
+                fs::remove_file(storage.path()).unwrap();
+                storage.append(b"some bytes").unwrap();
+                let mode = fs::metadata(storage.path()).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o600);
+            }
+        }
+
+        it "should replace a temporary file left behind" {
+            fs::File::create("test_dir/file_storage.tmp").unwrap().write_all(b"stale").unwrap();
+            storage.write(b"some bytes").unwrap();
+
+            assert!(!path::Path::new("test_dir/file_storage.tmp").exists());
+            storage.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"some bytes".to_vec());
+        }
+
+        it "should append to the file" {
+            storage.append(b"some").unwrap();
+            storage.append(b" bytes").unwrap();
+            storage.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"some bytes".to_vec());
+        }
+
+        it "should remove the file when erased" {
+            storage.write(b"some bytes").unwrap();
+            assert!(storage.modified().is_some());
+
+            storage.erase().unwrap();
+            assert!(!storage.path().exists());
+            storage.erase().unwrap();
+        }
+    }
+
+    describe! bundle {
+        before_each {
+            let inner = Arc::new(MemoryStorage::new());
+            let bundle = Arc::new(Bundle::new(Box::new(inner.clone())));
+            let config = Bundle::section(&bundle, "config");
+            let key = Bundle::section(&bundle, "key");
+            let mut buffer: Vec<u8> = Vec::new();
+        }
+
+        it "should return NotFound for a section that wasn't written" {
+            key.write(b"sealed key").unwrap();
+
+            assert_eq!(config.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::NotFound);
+        }
+
+        it "should keep each section separately" {
+            config.write(b"{}").unwrap();
+            key.write(b"sealed key").unwrap();
+            config.append(b" more").unwrap();
+
+            config.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"{} more".to_vec());
+            key.read(&mut buffer).unwrap();
+            assert_eq!(buffer, b"sealed key".to_vec());
+            assert_eq!(bundle.sections().unwrap().len(), 2);
+        }
+
+        it "should frame the sections with their lengths" {
+            key.write(b"abc").unwrap();
+
+            inner.read(&mut buffer).unwrap();
+            let mut expected = BUNDLE_MAGIC.to_vec();
+            expected.extend_from_slice(&[0, 0, 0, 3]);
+            expected.extend_from_slice(b"key");
+            expected.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 3]);
+            expected.extend_from_slice(b"abc");
+            assert_eq!(buffer, expected);
+        }
+
+        it "should erase the underlying storage with the last section" {
+            config.write(b"{}").unwrap();
+            key.write(b"sealed key").unwrap();
+
+            key.erase().unwrap();
+            assert_eq!(key.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::NotFound);
+            assert!(inner.read(&mut buffer).is_ok());
+
+            config.erase().unwrap();
+            key.erase().unwrap();
+            assert_eq!(inner.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::NotFound);
+        }
+
+        it "should reject a malformed bundle" {
+            inner.write(b"not a bundle").unwrap();
+            assert_eq!(key.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+            let mut truncated = BUNDLE_MAGIC.to_vec();
+            truncated.extend_from_slice(&[0, 0, 0, 3, b'k']);
+            inner.write(&truncated).unwrap();
+            assert_eq!(key.read(&mut buffer).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
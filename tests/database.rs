@@ -0,0 +1,61 @@
+#![feature(plugin)]
+#![cfg_attr(test, plugin(stainless))]
+
+extern crate vault_core;
+extern crate uuid;
+
+use std::env;
+use std::fs;
+use uuid::Uuid;
+use vault_core::database::{Database, DatabaseError};
+use vault_core::record::Record;
+
+static PASSWORD: &'static str = "My voice is my password, verify me";
+
+fn assert_same_record(a: &Record, b: &Record) {
+    assert_eq!(a.uuid, b.uuid);
+    assert_eq!(a.name, b.name);
+    assert_eq!(a.kind, b.kind);
+    assert_eq!(a.entries, b.entries);
+    assert_eq!(a.updated_at, b.updated_at);
+}
+
+describe! reopen {
+    before_each {
+        let path = env::temp_dir().join(format!("ironvault-{}", Uuid::new_v4()));
+        env::set_var("IRONVAULT_DATABASE", &path);
+
+        let created: Vec<Record> = {
+            let mut db = Database::create(PASSWORD.to_string());
+            db.add_record(Record::new_login("Bank".to_string(), "me".to_string(), "password1".to_string())).unwrap();
+            db.add_record(Record::new_secure_note("Safe".to_string(), "12-34-56".to_string())).unwrap();
+            db.fetch_records().clone()
+        };
+    }
+
+    after_each {
+        env::remove_var("IRONVAULT_DATABASE");
+        fs::remove_dir_all(&path).unwrap_or(());
+    }
+
+    it "should read back the same records with the right password" {
+        let db = Database::open(PASSWORD.to_string()).expect("Should open the database");
+        let records = db.fetch_records();
+
+        assert_eq!(records.len(), created.len());
+        for (a, b) in created.iter().zip(records) {
+            assert_same_record(a, b);
+        }
+        assert_eq!(records[0].password(), Some(&"password1".to_string()));
+    }
+
+    it "should return an error with the wrong password" {
+        assert_eq!(created.len(), 2);
+        let result = Database::open("My voice is not my password".to_string());
+
+        assert!(match result {
+            Err(DatabaseError::InvalidPassword) => true,
+            _ => false
+        });
+    }
+}